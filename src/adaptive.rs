@@ -0,0 +1,237 @@
+use std::sync::Mutex;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::{Duration, Instant};
+
+// concurrency limit that hill-climbs on aggregate throughput: every interval the bytes
+// downloaded are compared with the previous interval, one more slot is opened while that
+// keeps improving and one is closed when it drops. overload (429, 5xx, timeouts) halves
+// the limit and holds it there for a couple of intervals
+pub struct AdaptiveLimit {
+    min: usize,
+    max: usize,
+    interval: Duration,
+    semaphore: Semaphore,
+    state: Mutex<State>,
+}
+
+struct State {
+    limit: usize,
+    debt: usize, // slots to close as they're released, after lowering the limit
+    window_start: Instant,
+    window_bytes: u64,
+    last_throughput: Option<f64>,
+    hold_until: Option<Instant>,
+}
+
+// a running download, releasing it frees the slot unless the limit has been lowered since
+pub struct Slot<'a> {
+    limit: &'a AdaptiveLimit,
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut state = self.limit.state.lock().unwrap();
+        if let Some(permit) = self.permit.take() {
+            if state.debt > 0 {
+                state.debt -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl AdaptiveLimit {
+    // starts at `min` and ramps up from there
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        Self {
+            min,
+            max,
+            interval: Duration::from_secs(2),
+            semaphore: Semaphore::new(min),
+            state: Mutex::new(State {
+                limit: min,
+                debt: 0,
+                window_start: Instant::now(),
+                window_bytes: 0,
+                last_throughput: None,
+                hold_until: None,
+            }),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    pub async fn acquire(&self) -> Slot<'_> {
+        let permit = self.semaphore.acquire().await;
+        Slot {
+            limit: self,
+            permit: Some(permit),
+        }
+    }
+
+    // returns the new limit when it changed
+    pub fn record_success(&self, bytes: u64) -> Option<usize> {
+        self.record_bytes_at(bytes, Instant::now())
+    }
+
+    // returns the new limit when it changed
+    pub fn record_overload(&self) -> Option<usize> {
+        self.record_overload_at(Instant::now())
+    }
+
+    fn record_bytes_at(&self, bytes: u64, now: Instant) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.window_bytes += bytes;
+
+        let elapsed = now.duration_since(state.window_start);
+        if elapsed < self.interval {
+            return None;
+        }
+
+        let throughput = state.window_bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        let last = state.last_throughput.replace(throughput);
+        state.window_start = now;
+        state.window_bytes = 0;
+
+        if state.hold_until.is_some_and(|until| now < until) {
+            return None;
+        }
+
+        let target = match last {
+            None => state.limit + 1,
+            Some(last) if throughput > last * 1.05 => state.limit + 1,
+            Some(last) if throughput < last * 0.8 => state.limit.saturating_sub(1),
+            Some(_) => state.limit,
+        };
+        self.set_limit(&mut state, target)
+    }
+
+    fn record_overload_at(&self, now: Instant) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        // a burst of failures is a single overload, the limit was already lowered for it
+        if state.hold_until.is_some_and(|until| now < until) {
+            return None;
+        }
+        state.hold_until = Some(now + self.interval * 2);
+        state.last_throughput = None;
+        state.window_start = now;
+        state.window_bytes = 0;
+
+        let target = state.limit / 2;
+        self.set_limit(&mut state, target)
+    }
+
+    fn set_limit(&self, state: &mut State, target: usize) -> Option<usize> {
+        let target = target.max(self.min).min(self.max);
+        if target == state.limit {
+            return None;
+        }
+
+        if target > state.limit {
+            for _ in state.limit..target {
+                if state.debt > 0 {
+                    state.debt -= 1;
+                } else {
+                    self.semaphore.add_permits(1);
+                }
+            }
+        } else {
+            // close idle slots right away, busy ones as they're released
+            for _ in target..state.limit {
+                match self.semaphore.try_acquire() {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => state.debt += 1,
+                }
+            }
+        }
+
+        state.limit = target;
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+mod adaptive_tests {
+    use super::*;
+
+    fn limiter() -> AdaptiveLimit {
+        AdaptiveLimit::new(1, 4).with_interval(Duration::from_secs(1))
+    }
+
+    #[tokio::test]
+    async fn ramps_up_while_throughput_grows() {
+        let limit = limiter();
+        let start = Instant::now();
+        let second = |n: u64| start + Duration::from_secs(n);
+
+        assert_eq!(limit.record_bytes_at(100, start), None);
+        assert_eq!(limit.record_bytes_at(100, second(1)), Some(2));
+        assert_eq!(limit.record_bytes_at(300, second(2)), Some(3));
+        assert_eq!(limit.record_bytes_at(500, second(3)), Some(4));
+        // capped at max
+        assert_eq!(limit.record_bytes_at(700, second(4)), None);
+        // flat throughput holds, a drop backs off
+        assert_eq!(limit.record_bytes_at(700, second(5)), None);
+        assert_eq!(limit.record_bytes_at(100, second(6)), Some(3));
+    }
+
+    #[tokio::test]
+    async fn halves_on_overload_and_holds() {
+        let limit = limiter();
+        let start = Instant::now();
+        for n in 1..=3 {
+            limit.record_bytes_at(100 * n, start + Duration::from_secs(n));
+        }
+        assert_eq!(limit.limit(), 4);
+
+        assert_eq!(
+            limit.record_overload_at(start + Duration::from_secs(4)),
+            Some(2)
+        );
+        assert_eq!(
+            limit.record_overload_at(start + Duration::from_secs(4)),
+            None
+        );
+        // still held, even though throughput grows
+        assert_eq!(
+            limit.record_bytes_at(1000, start + Duration::from_secs(5)),
+            None
+        );
+        assert_eq!(limit.limit(), 2);
+    }
+
+    #[tokio::test]
+    async fn lowering_the_limit_closes_busy_slots_on_release() {
+        let limit = AdaptiveLimit::new(1, 4);
+        {
+            let mut state = limit.state.lock().unwrap();
+            limit.set_limit(&mut state, 3);
+        }
+
+        let a = limit.acquire().await;
+        let b = limit.acquire().await;
+        let c = limit.acquire().await;
+        {
+            let mut state = limit.state.lock().unwrap();
+            limit.set_limit(&mut state, 1);
+            assert_eq!(state.debt, 2);
+        }
+
+        drop(a);
+        drop(b);
+        assert_eq!(limit.semaphore.available_permits(), 0);
+        drop(c);
+        assert_eq!(limit.semaphore.available_permits(), 1);
+    }
+}
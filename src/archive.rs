@@ -0,0 +1,117 @@
+use anyhow::Context;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// youtube-dl style download archive, one `<extractor> <id>` line per completed lesson part
+const EXTRACTOR: &str = "schoolism";
+
+pub struct DownloadArchive {
+    path: PathBuf,
+    ids: HashSet<String>,
+}
+
+impl DownloadArchive {
+    // a missing file is treated as an empty archive, it's created on first record
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let ids = match std::fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| match line.trim().split_once(char::is_whitespace) {
+                    Some((EXTRACTOR, id)) => Some(id.trim().to_string()),
+                    _ => None,
+                })
+                .filter(|id| !id.is_empty())
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e).context("could not read download archive"),
+        };
+
+        Ok(Self {
+            path: path.into(),
+            ids,
+        })
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    pub fn record(&mut self, id: &str) -> anyhow::Result<()> {
+        if !self.ids.insert(id.into()) {
+            return Ok(());
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context("could not open download archive")?;
+        writeln!(file, "{} {}", EXTRACTOR, id).context("could not write to download archive")
+    }
+}
+
+// stable id for a lesson part: the lesson link, the playlist path and the quality
+// the playlist query string and host are dropped, they carry signatures and cdn names
+pub fn part_id(lesson_link: &str, part_url: &str, hq: bool) -> String {
+    let without_query = part_url.split(['?', '#']).next().unwrap_or_default();
+    let path = match without_query.split_once("://") {
+        Some((_, rest)) => rest.find('/').map(|i| &rest[i..]).unwrap_or("/"),
+        None => without_query,
+    };
+
+    let quality = if hq { "hq" } else { "nq" };
+    format!("{}:{}:{}", lesson_link, path, quality)
+}
+
+#[cfg(test)]
+mod archive_tests {
+    use super::*;
+
+    #[test]
+    fn part_id_ignores_signatures_and_hosts() {
+        let a = part_id(
+            "watchLesson.php?lessonID=12",
+            "https://cdn1.example.com/v/12/1/index.m3u8?Signature=abc&Expires=1",
+            true,
+        );
+        let b = part_id(
+            "watchLesson.php?lessonID=12",
+            "https://cdn2.example.com/v/12/1/index.m3u8?Signature=def&Expires=2",
+            true,
+        );
+        assert_eq!(a, b);
+        assert_eq!(a, "watchLesson.php?lessonID=12:/v/12/1/index.m3u8:hq");
+        // both qualities can be archived
+        assert_ne!(
+            a,
+            part_id("watchLesson.php?lessonID=12", "/v/12/1/index.m3u8", false)
+        );
+    }
+
+    #[test]
+    fn records_persist_across_opens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.txt");
+
+        let mut archive = DownloadArchive::open(&path).unwrap();
+        assert!(!archive.contains("a"));
+        archive.record("a").unwrap();
+        archive.record("a").unwrap();
+        archive.record("b").unwrap();
+
+        let archive = DownloadArchive::open(&path).unwrap();
+        assert!(archive.contains("a"));
+        assert!(archive.contains("b"));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "schoolism a\nschoolism b\n"
+        );
+
+        // other extractors, even with a matching prefix, are ignored
+        std::fs::write(&path, "schoolismfoo c\nyoutube d\nschoolism  e \n").unwrap();
+        let archive = DownloadArchive::open(&path).unwrap();
+        assert!(!archive.contains("c") && !archive.contains("d"));
+        assert!(archive.contains("e"));
+    }
+}
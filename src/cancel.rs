@@ -0,0 +1,115 @@
+use futures::future::{BoxFuture, FutureExt};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+// cooperative cancellation shared between whoever decides to stop (a ctrl-c handler, a
+// library user) and the download pipeline. clones observe the same token
+#[derive(Clone)]
+pub struct CancellationToken {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+    parent: Option<Box<CancellationToken>>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+            parent: None,
+        }
+    }
+
+    // a token that is cancelled along with this one, but can also be cancelled on its own
+    // without affecting this one, e.g. to stop a single job of a session
+    pub fn child(&self) -> Self {
+        Self {
+            parent: Some(Box::new(self.clone())),
+            ..Self::new()
+        }
+    }
+
+    pub fn cancel(&self) {
+        // every clone holds a receiver, so this can't fail while self is alive
+        let _ = self.sender.broadcast(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.receiver.borrow()
+            || self
+                .parent
+                .as_ref()
+                .is_some_and(|parent| parent.is_cancelled())
+    }
+
+    // resolves once the token, or one of its parents, is cancelled
+    pub fn cancelled(&self) -> BoxFuture<'_, ()> {
+        let own = async move {
+            let mut receiver = self.receiver.clone();
+            loop {
+                if *receiver.borrow() {
+                    return;
+                }
+                if receiver.recv().await.is_none() {
+                    // the sender is shared by every clone, so it can't be dropped before us
+                    futures::future::pending::<()>().await;
+                }
+            }
+        };
+
+        match &self.parent {
+            Some(parent) => futures::future::select(own.boxed(), parent.cancelled())
+                .map(|_| ())
+                .boxed(),
+            None => own.boxed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod cancel_tests {
+    use super::*;
+    use tokio::time::{delay_for, timeout, Duration};
+
+    #[tokio::test]
+    async fn clones_observe_cancellation() {
+        let token = CancellationToken::new();
+        let observer = token.clone();
+        assert!(!observer.is_cancelled());
+
+        let waiter = tokio::spawn(async move { observer.cancelled().await });
+        delay_for(Duration::from_millis(10)).await;
+        token.cancel();
+
+        timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(token.is_cancelled());
+        token.cancelled().await;
+    }
+
+    #[tokio::test]
+    async fn children_follow_their_parent_only() {
+        let parent = CancellationToken::new();
+        let child = parent.child();
+        let sibling = parent.child();
+
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(!parent.is_cancelled() && !sibling.is_cancelled());
+
+        parent.cancel();
+        timeout(Duration::from_secs(1), sibling.cancelled())
+            .await
+            .unwrap();
+        assert!(sibling.is_cancelled());
+    }
+}
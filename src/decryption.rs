@@ -11,6 +11,10 @@ pub struct Cipher {
 }
 
 impl Cipher {
+    pub fn new(key: Vec<u8>, iv: Vec<u8>) -> Self {
+        Self { key, iv }
+    }
+
     pub fn from_list(index: &crate::schoolism::client::SchoolismVideoList) -> Self {
         let key = index.key.clone();
         let iv = index.iv.clone();
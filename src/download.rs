@@ -0,0 +1,807 @@
+use anyhow::Context;
+use futures::future::BoxFuture;
+use futures::stream::StreamExt;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::time::{delay_for, Duration, Instant};
+
+use crate::adaptive::AdaptiveLimit;
+use crate::cancel::CancellationToken;
+use crate::decryption::Cipher;
+use crate::output::OrderedWriter;
+use crate::ratelimit::RateLimiter;
+use crate::schoolism::client::{ClientConnected, SchoolismVideoList};
+use crate::schoolism::refresh::RefreshablePart;
+
+// download orchestration shared by the cli and anything embedding selene
+// a `SegmentSource` knows how to fetch segment n, the `Downloader` fetches them concurrently,
+// retries failures, decrypts if given a cipher and writes them to a sink in order
+
+pub trait SegmentSource: Send + Sync {
+    fn segment_count(&self) -> usize;
+
+    // bytes of a single segment, counted against the rate limiter as they arrive
+    fn fetch<'a>(
+        &'a self,
+        idx: usize,
+        rate_limiter: Option<&'a RateLimiter>,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<u8>>>;
+}
+
+// already decrypted and validated, so no cipher is needed on the downloader
+impl SegmentSource for RefreshablePart {
+    fn segment_count(&self) -> usize {
+        self.list().files.len()
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        idx: usize,
+        rate_limiter: Option<&'a RateLimiter>,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<u8>>> {
+        Box::pin(self.download_segment(idx, rate_limiter))
+    }
+}
+
+// a fixed schoolism playlist, fetched with the session's media headers but never refreshed
+// segments come back encrypted, pair it with `Cipher::from_list`
+pub struct ListSegments {
+    client: Arc<ClientConnected>,
+    list: Arc<SchoolismVideoList>,
+}
+
+impl ListSegments {
+    pub fn new(client: Arc<ClientConnected>, list: Arc<SchoolismVideoList>) -> Self {
+        Self { client, list }
+    }
+}
+
+impl SegmentSource for ListSegments {
+    fn segment_count(&self) -> usize {
+        self.list.files.len()
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        idx: usize,
+        rate_limiter: Option<&'a RateLimiter>,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let url = self
+                .list
+                .files
+                .get(idx)
+                .context(format!("segment index [{}] does not exist", idx))?;
+            let resp = self.client.download_media(&self.list, url).await?;
+            read_body(resp, url, rate_limiter).await
+        })
+    }
+}
+
+// any list of segment urls, fetched with a plain client
+pub struct UrlSegments {
+    client: reqwest::Client,
+    urls: Vec<String>,
+}
+
+impl UrlSegments {
+    pub fn new(client: reqwest::Client, urls: Vec<String>) -> Self {
+        Self { client, urls }
+    }
+}
+
+impl SegmentSource for UrlSegments {
+    fn segment_count(&self) -> usize {
+        self.urls.len()
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        idx: usize,
+        rate_limiter: Option<&'a RateLimiter>,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let url = self
+                .urls
+                .get(idx)
+                .context(format!("segment index [{}] does not exist", idx))?;
+            let resp = self
+                .client
+                .get(url)
+                .send()
+                .await
+                .context(format!("failed to request segment [{}]", url))?;
+            if !resp.status().is_success() {
+                return Err(crate::http::StatusError {
+                    url: url.clone(),
+                    status: resp.status(),
+                }
+                .into());
+            }
+            read_body(resp, url, rate_limiter).await
+        })
+    }
+}
+
+// reads a response body chunk by chunk so the rate limiter sees the bytes as they arrive
+pub(crate) async fn read_body(
+    mut resp: reqwest::Response,
+    url: &str,
+    rate_limiter: Option<&RateLimiter>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    while let Some(chunk) = resp
+        .chunk()
+        .await
+        .context(format!("failed to read segment body [{}]", url))?
+    {
+        if let Some(limiter) = rate_limiter {
+            limiter.acquire(chunk.len() as u64).await;
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+#[derive(Clone, Debug)]
+pub enum DownloadEvent {
+    SegmentStarted {
+        idx: usize,
+    },
+    SegmentFinished {
+        idx: usize,
+        bytes: u64,
+        elapsed: Duration,
+    },
+    SegmentFailed {
+        idx: usize,
+        attempt: u32,
+        error: String,
+        retrying: bool,
+    },
+    // emitted whenever segments reach the sink
+    Progress {
+        segments_written: usize,
+        segments_total: usize,
+        bytes_written: u64,
+    },
+    Merged {
+        segments: usize,
+        bytes: u64,
+    },
+    // adaptive concurrency opened or closed slots
+    ConcurrencyChanged {
+        limit: usize,
+    },
+    // a segment didn't decrypt to a valid stream, it's fetched again
+    SegmentInvalid {
+        idx: usize,
+        attempt: u32,
+        problem: String,
+    },
+    // the source's urls expired while fetching `idx`, they're being resolved again
+    UrlsExpired {
+        idx: usize,
+    },
+}
+
+// returned by `Downloader::download` when its token was cancelled, after in-flight segments
+// finished and everything contiguous was flushed to the sink
+#[derive(Debug)]
+pub struct Cancelled {
+    pub segment_sizes: Vec<u64>, // segments in the sink, in order, including resumed ones
+    pub segment_count: usize,
+}
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "download cancelled after [{}] of [{}] segments",
+            self.segment_sizes.len(),
+            self.segment_count
+        )
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+// connections shared by several downloaders running at once, e.g. one per lesson part
+// waiting segments are served first come first served, and since each downloader only has
+// its own concurrency worth of segments waiting, parts get an even share of the pool
+pub struct ConnectionPool {
+    semaphore: Semaphore,
+    size: usize,
+}
+
+impl ConnectionPool {
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1);
+        Self {
+            semaphore: Semaphore::new(size),
+            size,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn available(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+}
+
+pub type EventCallback = Arc<dyn Fn(&DownloadEvent) + Send + Sync>;
+
+#[derive(Debug)]
+pub struct DownloadSummary {
+    pub segment_sizes: Vec<u64>, // in playlist order, as written to the sink
+    pub bytes: u64,
+}
+
+pub struct Downloader {
+    concurrency: usize,
+    window: Option<usize>,
+    retries: u32,
+    retry_delay: Duration,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    cipher: Option<Cipher>,
+    events: Option<EventCallback>,
+    cancellation: Option<CancellationToken>,
+    abort: Option<CancellationToken>,
+    completed: Vec<u64>,
+    adaptive: Option<AdaptiveLimit>,
+    pool: Option<Arc<ConnectionPool>>,
+}
+
+impl Default for Downloader {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            window: None,
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            rate_limiter: None,
+            cipher: None,
+            events: None,
+            cancellation: None,
+            abort: None,
+            completed: vec![],
+            adaptive: None,
+            pool: None,
+        }
+    }
+}
+
+impl Downloader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    // let throughput and server errors decide how many segments download at once, between
+    // `min` and `max`, instead of a fixed concurrency
+    pub fn with_adaptive_concurrency(mut self, min: usize, max: usize) -> Self {
+        let adaptive = AdaptiveLimit::new(min, max);
+        self.concurrency = max.max(1);
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    // every segment request also needs a connection from the shared pool
+    pub fn with_pool(mut self, pool: Arc<ConnectionPool>) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    // how many segments downloads may run ahead of the sink, twice the concurrency by default
+    // completed segments waiting on an earlier one are held in memory, so this bounds memory
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = Some(window.max(1));
+        self
+    }
+
+    // attempts per segment after the first, the delay grows linearly between attempts
+    pub fn with_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.retries = retries;
+        self.retry_delay = delay;
+        self
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    // decrypt every segment after fetching it, for sources handing out encrypted bytes
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    // once cancelled no new segments are started, in-flight ones finish and are written
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    // like a cancellation, but in-flight segments are dropped instead of waited for, what was
+    // written so far is still flushed and reported through `Cancelled`
+    pub fn with_abort(mut self, token: CancellationToken) -> Self {
+        self.abort = Some(token);
+        self
+    }
+
+    // sizes of the leading segments the sink already holds, from an interrupted download
+    pub fn with_completed(mut self, segment_sizes: Vec<u64>) -> Self {
+        self.completed = segment_sizes;
+        self
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .iter()
+            .chain(&self.abort)
+            .any(CancellationToken::is_cancelled)
+    }
+
+    async fn aborted(&self) {
+        match &self.abort {
+            Some(abort) => abort.cancelled().await,
+            None => futures::future::pending().await,
+        }
+    }
+
+    pub fn on_event<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&DownloadEvent) + Send + Sync + 'static,
+    {
+        self.events = Some(Arc::new(callback));
+        self
+    }
+
+    // events are dropped once the receiver is gone
+    pub fn with_event_channel(
+        self,
+        sender: tokio::sync::mpsc::UnboundedSender<DownloadEvent>,
+    ) -> Self {
+        self.on_event(move |event| {
+            let _ = sender.send(event.clone());
+        })
+    }
+
+    fn emit(&self, event: DownloadEvent) {
+        if let Some(callback) = &self.events {
+            callback(&event);
+        }
+    }
+
+    pub async fn download<S, W>(&self, source: &S, sink: W) -> anyhow::Result<DownloadSummary>
+    where
+        S: SegmentSource + ?Sized,
+        W: Write,
+    {
+        let total = source.segment_count();
+        if self.completed.len() > total {
+            anyhow::bail!(
+                "[{}] segments already completed, source only has [{}]",
+                self.completed.len(),
+                total
+            );
+        }
+
+        let mut writer = OrderedWriter::resume(sink, self.completed.clone());
+        let window = Arc::new(Semaphore::new(self.window.unwrap_or(self.concurrency * 2)));
+
+        // permits are taken in segment order, so the next segment to write always has one
+        // checking for cancellation after getting one stops new segments without losing any
+        let permits = futures::stream::iter(writer.position()..total)
+            .then(|idx| {
+                let window = window.clone();
+                async move { (idx, window.acquire_owned().await) }
+            })
+            .take_while(|_| futures::future::ready(!self.is_cancelled()));
+
+        let segments = permits
+            .map(|(idx, permit)| async move {
+                let bytes = self.fetch_segment(source, idx).await?;
+                Ok::<_, anyhow::Error>((idx, bytes, permit))
+            })
+            .buffer_unordered(self.concurrency);
+        futures::pin_mut!(segments);
+
+        // permits of segments waiting on an earlier one are held until they're written
+        let mut held = BTreeMap::new();
+        let mut bytes_written = writer.segment_sizes().iter().sum();
+        loop {
+            let segment = tokio::select! {
+                segment = segments.next() => segment,
+                _ = self.aborted() => break,
+            };
+            let (idx, bytes, permit) = match segment {
+                Some(Ok(segment)) => segment,
+                // failures aren't retried once cancelled, they end up as part of the cancellation
+                Some(Err(_)) if self.is_cancelled() => continue,
+                Some(Err(e)) => return Err(e),
+                None => break,
+            };
+            held.insert(idx, permit);
+
+            let before = writer.position();
+            writer.push(idx, bytes)?;
+            held = held.split_off(&writer.position());
+
+            if writer.position() > before {
+                bytes_written = writer.segment_sizes().iter().sum();
+                self.emit(DownloadEvent::Progress {
+                    segments_written: writer.position(),
+                    segments_total: total,
+                    bytes_written,
+                });
+            }
+        }
+
+        if self.is_cancelled() {
+            let (_, segment_sizes) = writer.finish_partial()?;
+            return Err(Cancelled {
+                segment_sizes,
+                segment_count: total,
+            }
+            .into());
+        }
+
+        let (_, segment_sizes) = writer.finish()?;
+        self.emit(DownloadEvent::Merged {
+            segments: segment_sizes.len(),
+            bytes: bytes_written,
+        });
+
+        Ok(DownloadSummary {
+            segment_sizes,
+            bytes: bytes_written,
+        })
+    }
+
+    async fn fetch_segment<S>(&self, source: &S, idx: usize) -> anyhow::Result<Vec<u8>>
+    where
+        S: SegmentSource + ?Sized,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let slot = match &self.adaptive {
+                Some(adaptive) => Some(adaptive.acquire().await),
+                None => None,
+            };
+            let connection = match &self.pool {
+                Some(pool) => Some(pool.semaphore.acquire().await),
+                None => None,
+            };
+            self.emit(DownloadEvent::SegmentStarted { idx });
+            let start = Instant::now();
+
+            let result = source
+                .fetch(idx, self.rate_limiter.as_deref())
+                .await
+                .and_then(|mut bytes| match &self.cipher {
+                    Some(cipher) => Ok(cipher.decrypt(&mut bytes)?.to_vec()),
+                    None => Ok(bytes),
+                });
+
+            match result {
+                Ok(bytes) => {
+                    drop(connection);
+                    drop(slot);
+                    if let Some(limit) = self
+                        .adaptive
+                        .as_ref()
+                        .and_then(|adaptive| adaptive.record_success(bytes.len() as u64))
+                    {
+                        self.emit(DownloadEvent::ConcurrencyChanged { limit });
+                    }
+                    self.emit(DownloadEvent::SegmentFinished {
+                        idx,
+                        bytes: bytes.len() as u64,
+                        elapsed: start.elapsed(),
+                    });
+                    return Ok(bytes);
+                }
+                Err(e) => {
+                    drop(connection);
+                    drop(slot);
+                    if let Some(limit) = self
+                        .adaptive
+                        .as_ref()
+                        .filter(|_| crate::http::is_overload(&e))
+                        .and_then(AdaptiveLimit::record_overload)
+                    {
+                        self.emit(DownloadEvent::ConcurrencyChanged { limit });
+                    }
+
+                    let retrying = attempt <= self.retries && !self.is_cancelled();
+                    self.emit(DownloadEvent::SegmentFailed {
+                        idx,
+                        attempt,
+                        error: format!("{:#}", e),
+                        retrying,
+                    });
+                    if !retrying {
+                        return Err(e.context(format!(
+                            "segment [{}] failed after [{}] attempts",
+                            idx, attempt
+                        )));
+                    }
+                    delay_for(self.retry_delay * attempt).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod download_tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    // segment n is n+1 copies of the byte n, finishing in reverse order, failing once if asked
+    struct FakeSource {
+        count: usize,
+        flaky: Option<usize>,
+        calls: AtomicUsize,
+    }
+
+    impl SegmentSource for FakeSource {
+        fn segment_count(&self) -> usize {
+            self.count
+        }
+
+        fn fetch<'a>(
+            &'a self,
+            idx: usize,
+            _: Option<&'a RateLimiter>,
+        ) -> BoxFuture<'a, anyhow::Result<Vec<u8>>> {
+            Box::pin(async move {
+                let call = self.calls.fetch_add(1, Ordering::SeqCst);
+                delay_for(Duration::from_millis(((self.count - idx) * 5) as u64)).await;
+                if self.flaky == Some(idx) && call == idx {
+                    anyhow::bail!("flaky segment");
+                }
+                Ok(vec![idx as u8; idx + 1])
+            })
+        }
+    }
+
+    fn source(count: usize, flaky: Option<usize>) -> FakeSource {
+        FakeSource {
+            count,
+            flaky,
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_segments_in_order_and_reports_events() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        let downloader = Downloader::new()
+            .with_concurrency(3)
+            .on_event(move |event| recorded.lock().unwrap().push(event.clone()));
+
+        let mut out = vec![];
+        let summary = downloader
+            .download(&source(4, None), &mut out)
+            .await
+            .unwrap();
+
+        assert_eq!(out, vec![0, 1, 1, 2, 2, 2, 3, 3, 3, 3]);
+        assert_eq!(summary.segment_sizes, vec![1, 2, 3, 4]);
+        assert_eq!(summary.bytes, 10);
+
+        let events = events.lock().unwrap();
+        let finished = events
+            .iter()
+            .filter(|e| matches!(e, DownloadEvent::SegmentFinished { .. }))
+            .count();
+        assert_eq!(finished, 4);
+        assert!(matches!(
+            events.last(),
+            Some(DownloadEvent::Merged {
+                segments: 4,
+                bytes: 10
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn retries_failed_segments() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let downloader = Downloader::new()
+            .with_concurrency(1)
+            .with_retries(1, Duration::from_millis(1))
+            .with_event_channel(sender);
+
+        let mut out = vec![];
+        downloader
+            .download(&source(2, Some(1)), &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, vec![0, 1, 1]);
+
+        drop(downloader);
+        let mut failures = 0;
+        while let Some(event) = receiver.recv().await {
+            if let DownloadEvent::SegmentFailed { idx, retrying, .. } = event {
+                assert_eq!(idx, 1);
+                assert!(retrying);
+                failures += 1;
+            }
+        }
+        assert_eq!(failures, 1);
+    }
+
+    #[tokio::test]
+    async fn cancellation_keeps_written_segments() {
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        let downloader = Downloader::new()
+            .with_concurrency(1)
+            .with_completed(vec![1])
+            .with_cancellation(token)
+            .on_event(move |event| {
+                if let DownloadEvent::SegmentFinished { idx: 2, .. } = event {
+                    cancel.cancel();
+                }
+            });
+
+        let mut out = vec![0];
+        let err = downloader
+            .download(&source(5, None), &mut out)
+            .await
+            .unwrap_err();
+
+        let cancelled = err.downcast_ref::<Cancelled>().unwrap();
+        assert_eq!(cancelled.segment_sizes, vec![1, 2, 3]);
+        assert_eq!(out, vec![0, 1, 1, 2, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn failures_after_cancellation_still_cancel() {
+        // segment 1 fails once the download has been cancelled
+        struct FailingSource;
+
+        impl SegmentSource for FailingSource {
+            fn segment_count(&self) -> usize {
+                3
+            }
+
+            fn fetch<'a>(
+                &'a self,
+                idx: usize,
+                _: Option<&'a RateLimiter>,
+            ) -> BoxFuture<'a, anyhow::Result<Vec<u8>>> {
+                Box::pin(async move {
+                    if idx == 1 {
+                        delay_for(Duration::from_millis(20)).await;
+                        anyhow::bail!("connection reset");
+                    }
+                    Ok(vec![idx as u8; idx + 1])
+                })
+            }
+        }
+
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        let downloader = Downloader::new()
+            .with_concurrency(2)
+            .with_cancellation(token)
+            .on_event(move |event| {
+                if let DownloadEvent::SegmentStarted { idx: 1 } = event {
+                    cancel.cancel();
+                }
+            });
+
+        let mut out = vec![];
+        let err = downloader
+            .download(&FailingSource, &mut out)
+            .await
+            .unwrap_err();
+
+        let cancelled = err.downcast_ref::<Cancelled>().unwrap();
+        assert_eq!(cancelled.segment_sizes, vec![1]);
+        assert_eq!(out, vec![0]);
+    }
+
+    #[tokio::test]
+    async fn abort_drops_in_flight_segments() {
+        // everything from segment 2 on never finishes
+        struct StuckSource;
+
+        impl SegmentSource for StuckSource {
+            fn segment_count(&self) -> usize {
+                5
+            }
+
+            fn fetch<'a>(
+                &'a self,
+                idx: usize,
+                _: Option<&'a RateLimiter>,
+            ) -> BoxFuture<'a, anyhow::Result<Vec<u8>>> {
+                Box::pin(async move {
+                    if idx >= 2 {
+                        futures::future::pending::<()>().await;
+                    }
+                    Ok(vec![idx as u8; idx + 1])
+                })
+            }
+        }
+
+        let token = CancellationToken::new();
+        let abort = token.clone();
+        tokio::spawn(async move {
+            delay_for(Duration::from_millis(20)).await;
+            abort.cancel();
+        });
+
+        let downloader = Downloader::new().with_concurrency(2).with_abort(token);
+        let mut out = vec![];
+        let err = tokio::time::timeout(
+            Duration::from_secs(1),
+            downloader.download(&StuckSource, &mut out),
+        )
+        .await
+        .unwrap()
+        .unwrap_err();
+
+        let cancelled = err.downcast_ref::<Cancelled>().unwrap();
+        assert_eq!(cancelled.segment_sizes, vec![1, 2]);
+        assert_eq!(out, vec![0, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn parts_share_the_connection_pool() {
+        let pool = Arc::new(ConnectionPool::new(2));
+        let busy = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let downloader = |busy: Arc<AtomicUsize>, peak: Arc<AtomicUsize>| {
+            Downloader::new()
+                .with_concurrency(3)
+                .with_pool(pool.clone())
+                .on_event(move |event| match event {
+                    DownloadEvent::SegmentStarted { .. } => {
+                        let now = busy.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                    }
+                    DownloadEvent::SegmentFinished { .. } => {
+                        busy.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    _ => {}
+                })
+        };
+        let a = downloader(busy.clone(), peak.clone());
+        let b = downloader(busy.clone(), peak.clone());
+        let (source_a, source_b) = (source(4, None), source(4, None));
+
+        let (out_a, out_b) =
+            futures::join!(a.download(&source_a, vec![]), b.download(&source_b, vec![]));
+        assert_eq!(out_a.unwrap().bytes, 10);
+        assert_eq!(out_b.unwrap().bytes, 10);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.available(), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let downloader = Downloader::new().with_retries(0, Duration::from_millis(1));
+        let result = downloader.download(&source(2, Some(0)), vec![]).await;
+        assert!(result.is_err());
+    }
+}
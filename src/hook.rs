@@ -0,0 +1,112 @@
+use anyhow::Context;
+use tokio::process::Command;
+
+// runs a user supplied command through the shell, with values passed as `SELENE_<NAME>`
+// environment variables so they never need quoting
+pub async fn run(command: &str, values: &[(&str, String)]) -> anyhow::Result<()> {
+    let mut process = shell(command);
+    for (name, value) in values {
+        process.env(format!("SELENE_{}", name.to_uppercase()), value);
+    }
+
+    let status = process
+        .status()
+        .await
+        .context(format!("could not run hook [{}]", command))?;
+    if !status.success() {
+        anyhow::bail!("hook [{}] failed with [{}]", command, status);
+    }
+    Ok(())
+}
+
+// fills `{name}` placeholders in a command template with shell quoted values, other braces
+// are left alone so the shell's own syntax keeps working
+pub fn expand(template: &str, values: &[(&str, String)]) -> String {
+    let mut command = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        command.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let placeholder = rest[1..]
+            .find('}')
+            .and_then(|end| values.iter().find(|(name, _)| *name == &rest[1..=end]));
+        match placeholder {
+            Some((name, value)) => {
+                command.push_str(&quote(value));
+                rest = &rest[name.len() + 2..];
+            }
+            None => {
+                command.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    command.push_str(rest);
+    command
+}
+
+#[cfg(windows)]
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+#[cfg(not(windows))]
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut process = Command::new("cmd");
+    process.arg("/C").arg(command);
+    process
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut process = Command::new("sh");
+    process.arg("-c").arg(command);
+    process
+}
+
+#[cfg(all(test, not(windows)))]
+mod hook_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn passes_values_as_environment() {
+        let values = [("lesson", "3".to_string())];
+        run("test \"$SELENE_LESSON\" = 3", &values).await.unwrap();
+
+        let err = run("exit 4", &values).await.unwrap_err();
+        assert!(err.to_string().contains("exit 4"));
+    }
+
+    #[tokio::test]
+    async fn expands_quoted_placeholders() {
+        let values = [
+            ("path", "it's here/a b.mp4".to_string()),
+            ("lesson", "3".to_string()),
+        ];
+        let command = expand(
+            "test {path} = \"$SELENE_PATH\" && echo ${HOME} {other}",
+            &values,
+        );
+        assert_eq!(
+            command,
+            "test 'it'\\''s here/a b.mp4' = \"$SELENE_PATH\" && echo ${HOME} {other}"
+        );
+        // values aren't expanded again
+        assert_eq!(
+            expand(
+                "{lesson} {path}",
+                &[("lesson", "{path}".into()), ("path", "a".into())]
+            ),
+            "'{path}' 'a'"
+        );
+        run(&expand("test {path} = \"$SELENE_PATH\"", &values), &values)
+            .await
+            .unwrap();
+    }
+}
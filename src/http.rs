@@ -0,0 +1,207 @@
+use anyhow::Context;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// chrome user agent
+pub const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 6.1; WOW64) \
+    AppleWebKit/537.36 (KHTML, like Gecko) \
+    Chrome/86.0.4230.1 \
+    Safari/537.36";
+
+// firefox user agent
+// const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:82.0) \
+//     Gecko/20100101 Firefox/82.0";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IpVersion {
+    Any,
+    V4,
+    V6,
+}
+
+// settings shared by every http client selene creates, page and segment requests alike
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    pub proxy: Option<String>, // http://, https:// or socks5:// url
+    pub connect_timeout: Option<Duration>,
+    pub timeout: Option<Duration>, // whole request, including reading the body
+    pub user_agent: String,
+    pub headers: Vec<(String, String)>,
+    pub root_certificates: Vec<PathBuf>, // pem or der encoded
+    pub ip_version: IpVersion,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            connect_timeout: None,
+            timeout: None,
+            user_agent: DEFAULT_USER_AGENT.into(),
+            headers: vec![],
+            root_certificates: vec![],
+            ip_version: IpVersion::Any,
+        }
+    }
+}
+
+impl HttpConfig {
+    // `Name: value`, as given on the command line
+    pub fn add_header(&mut self, header: &str) -> anyhow::Result<()> {
+        let (name, value) = header.split_once(':').context(format!(
+            "invalid header, expected [name: value]: [{}]",
+            header
+        ))?;
+        let name = name.trim();
+        if name.is_empty() {
+            anyhow::bail!("invalid header, empty name: [{}]", header);
+        }
+
+        self.headers.push((name.into(), value.trim().into()));
+        Ok(())
+    }
+
+    pub fn client_builder(&self) -> anyhow::Result<reqwest::ClientBuilder> {
+        let mut builder = reqwest::ClientBuilder::new().user_agent(&self.user_agent);
+
+        if let Some(proxy) = &self.proxy {
+            let proxy =
+                reqwest::Proxy::all(proxy).context(format!("invalid proxy url: [{}]", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .context(format!("invalid header name: [{}]", name))?;
+            let value = reqwest::header::HeaderValue::from_str(value)
+                .context(format!("invalid header value: [{}]", value))?;
+            headers.append(name, value);
+        }
+        builder = builder.default_headers(headers);
+
+        for path in &self.root_certificates {
+            let bytes = std::fs::read(path)
+                .context(format!("could not read certificate [{}]", path.display()))?;
+            let certificate = reqwest::Certificate::from_pem(&bytes)
+                .or_else(|_| reqwest::Certificate::from_der(&bytes))
+                .context(format!("invalid certificate [{}]", path.display()))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        // binding to the unspecified address of a family restricts connections to it
+        builder = match self.ip_version {
+            IpVersion::Any => builder,
+            IpVersion::V4 => builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            IpVersion::V6 => builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        };
+
+        Ok(builder)
+    }
+
+    pub fn build(&self) -> anyhow::Result<reqwest::Client> {
+        self.client_builder()?
+            .build()
+            .context("could not create new http client")
+    }
+}
+
+// a request that came back with an unexpected status
+#[derive(Debug)]
+pub struct StatusError {
+    pub url: String,
+    pub status: reqwest::StatusCode,
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request failed [{}]: [{}]", self.url, self.status)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+// the server is asking us to slow down, or struggling: 429, any 5xx, or a timeout
+pub fn is_overload(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<StatusError>() {
+            return e.status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                || e.status.is_server_error();
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_timeout()
+                || e.status().is_some_and(|status| {
+                    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+                });
+        }
+        false
+    })
+}
+
+#[cfg(test)]
+mod http_config_tests {
+    use super::*;
+
+    #[test]
+    fn parses_headers() {
+        let mut config = HttpConfig::default();
+        config.add_header("X-Token: a:b ").unwrap();
+        assert_eq!(config.headers, vec![("X-Token".into(), "a:b".into())]);
+
+        assert!(config.add_header("no separator").is_err());
+        assert!(config.add_header(": empty name").is_err());
+    }
+
+    #[test]
+    fn builds_with_all_options() {
+        let mut config = HttpConfig {
+            proxy: Some("socks5://127.0.0.1:1080".into()),
+            connect_timeout: Some(Duration::from_secs(5)),
+            timeout: Some(Duration::from_secs(60)),
+            user_agent: "selene".into(),
+            ip_version: IpVersion::V4,
+            ..HttpConfig::default()
+        };
+        config.add_header("Accept-Language: en").unwrap();
+
+        assert!(config.build().is_ok());
+    }
+
+    #[test]
+    fn detects_overload_statuses() {
+        let status = |code: u16| {
+            anyhow::Error::from(StatusError {
+                url: "https://example.com/seg.ts".into(),
+                status: reqwest::StatusCode::from_u16(code).unwrap(),
+            })
+            .context("segment failed")
+        };
+
+        assert!(is_overload(&status(429)));
+        assert!(is_overload(&status(503)));
+        assert!(!is_overload(&status(404)));
+        assert!(!is_overload(&anyhow::anyhow!("bad padding")));
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let mut config = HttpConfig::default();
+        config.add_header("Bad Name: x").unwrap();
+        assert!(config.build().is_err());
+
+        let config = HttpConfig {
+            root_certificates: vec!["/does/not/exist.pem".into()],
+            ..HttpConfig::default()
+        };
+        assert!(config.build().is_err());
+    }
+}
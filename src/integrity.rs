@@ -0,0 +1,291 @@
+use anyhow::Context;
+use std::collections::HashMap;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use crate::ts::{self, PACKET_SIZE};
+
+// checks run on the merged output before it's recorded anywhere
+// segment boundaries come from the merge itself, durations from the media playlist
+
+const TIMESTAMP_HZ: f64 = 90_000.0;
+const TIMESTAMP_WRAP: u64 = 1 << 33;
+
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+    pub expected_duration: f64, // seconds, sum of `EXTINF`
+    pub actual_duration: Option<f64>,
+    pub segments_expected: usize,
+    pub segments_found: usize,
+    pub issues: Vec<String>,
+}
+
+impl IntegrityReport {
+    pub fn passed(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl std::fmt::Display for IntegrityReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let actual = self
+            .actual_duration
+            .map(|d| format!("{:.2}s", d))
+            .unwrap_or_else(|| "unknown".into());
+        write!(
+            f,
+            "integrity check {}: duration [{}] of [{:.2}s], segments [{}] of [{}]",
+            if self.passed() { "passed" } else { "FAILED" },
+            actual,
+            self.expected_duration,
+            self.segments_found,
+            self.segments_expected
+        )?;
+        for issue in &self.issues {
+            write!(f, "\n  - {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+// timestamps of one elementary stream, unwrapped past the 33 bit rollover
+#[derive(Default)]
+struct StreamClock {
+    first: Option<u64>,
+    last: u64,
+    offset: u64,
+}
+
+impl StreamClock {
+    fn push(&mut self, raw: u64) -> (u64, bool) {
+        let mut ts = raw + self.offset;
+        if self.first.is_some() && ts < self.last && self.last - ts > TIMESTAMP_WRAP / 2 {
+            self.offset += TIMESTAMP_WRAP;
+            ts += TIMESTAMP_WRAP;
+        }
+
+        let monotonic = self.first.is_none() || ts >= self.last;
+        self.first.get_or_insert(ts);
+        self.last = ts;
+        (ts, monotonic)
+    }
+
+    fn span(&self) -> Option<f64> {
+        self.first
+            .map(|first| (self.last.saturating_sub(first)) as f64 / TIMESTAMP_HZ)
+    }
+}
+
+// `segment_sizes` are the byte lengths of each decrypted segment, in merge order
+pub fn check_output(
+    path: &Path,
+    durations: &[f64],
+    segment_sizes: &[u64],
+) -> anyhow::Result<IntegrityReport> {
+    let file = std::fs::File::open(path).context(format!(
+        "could not open output for integrity check [{}]",
+        path.display()
+    ))?;
+    let size = file
+        .metadata()
+        .context("could not read output metadata")?
+        .len();
+
+    check_stream(BufReader::new(file), size, durations, segment_sizes)
+}
+
+pub fn check_stream<R: Read>(
+    mut reader: R,
+    size: u64,
+    durations: &[f64],
+    segment_sizes: &[u64],
+) -> anyhow::Result<IntegrityReport> {
+    let mut report = IntegrityReport {
+        expected_duration: durations.iter().sum(),
+        segments_expected: durations.len(),
+        ..IntegrityReport::default()
+    };
+
+    if segment_sizes.len() != durations.len() {
+        report.issues.push(format!(
+            "merged [{}] segments, playlist lists [{}]",
+            segment_sizes.len(),
+            durations.len()
+        ));
+    }
+
+    let merged: u64 = segment_sizes.iter().sum();
+    if merged != size {
+        report.issues.push(format!(
+            "output is [{}] bytes, segments add up to [{}]",
+            size, merged
+        ));
+    }
+
+    // segment index for every byte offset, via the cumulative sizes
+    let boundaries: Vec<u64> = segment_sizes
+        .iter()
+        .scan(0, |end, size| {
+            *end += size;
+            Some(*end)
+        })
+        .collect();
+
+    let mut clocks: HashMap<u16, StreamClock> = HashMap::new();
+    let mut segment_starts: Vec<Option<u64>> = vec![None; segment_sizes.len()];
+    let mut backwards = 0;
+    let mut packet = vec![0; PACKET_SIZE];
+    let mut offset = 0u64;
+    let mut segment = 0;
+
+    loop {
+        match reader.read_exact(&mut packet) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e).context("could not read output for integrity check"),
+        }
+
+        while segment < boundaries.len() && offset >= boundaries[segment] {
+            segment += 1;
+        }
+        offset += PACKET_SIZE as u64;
+
+        let parsed = match ts::parse_packet(&packet) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        let timestamp = match parsed
+            .payload
+            .filter(|_| parsed.payload_unit_start)
+            .and_then(ts::pes_timestamp)
+        {
+            Some(timestamp) => timestamp,
+            None => continue,
+        };
+
+        let (timestamp, monotonic) = clocks.entry(parsed.pid).or_default().push(timestamp);
+        if !monotonic {
+            backwards += 1;
+            if backwards == 1 {
+                report.issues.push(format!(
+                    "timestamps go backwards on pid [{:#x}] in segment [{}]",
+                    parsed.pid, segment
+                ));
+            }
+        }
+
+        if let Some(start) = segment_starts.get_mut(segment) {
+            start.get_or_insert(timestamp);
+        }
+    }
+
+    if backwards > 1 {
+        report
+            .issues
+            .push(format!("[{}] timestamps go backwards", backwards));
+    }
+
+    // every segment should carry media, and start later than the one before it
+    report.segments_found = segment_starts.iter().filter(|s| s.is_some()).count();
+    let missing: Vec<String> = segment_starts
+        .iter()
+        .enumerate()
+        .filter(|(_, start)| start.is_none())
+        .map(|(i, _)| i.to_string())
+        .collect();
+    if !missing.is_empty() {
+        report.issues.push(format!(
+            "segments without any media timestamps: [{}]",
+            missing.join(", ")
+        ));
+    }
+
+    let starts: Vec<(usize, u64)> = segment_starts
+        .iter()
+        .enumerate()
+        .filter_map(|(i, start)| start.map(|s| (i, s)))
+        .collect();
+    if let Some(pair) = starts.windows(2).find(|pair| pair[1].1 <= pair[0].1) {
+        report.issues.push(format!(
+            "segment [{}] starts before segment [{}], segments are out of order",
+            pair[1].0, pair[0].0
+        ));
+    }
+
+    // the timestamp span misses the length of the final frame, hence the tolerance
+    report.actual_duration = clocks
+        .values()
+        .filter_map(StreamClock::span)
+        .fold(None, |max: Option<f64>, span| {
+            Some(max.map_or(span, |m| m.max(span)))
+        });
+    match report.actual_duration {
+        Some(actual) => {
+            let tolerance = (report.expected_duration * 0.02).max(1.0);
+            if (actual - report.expected_duration).abs() > tolerance {
+                report.issues.push(format!(
+                    "duration [{:.2}s] differs from the playlist's [{:.2}s]",
+                    actual, report.expected_duration
+                ));
+            }
+        }
+        None => report.issues.push("no media timestamps found".into()),
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod integrity_tests {
+    use super::*;
+    use crate::ts::test_stream::timed_segment;
+
+    // four 2 second segments at 25fps
+    fn segments() -> Vec<Vec<u8>> {
+        (0..4u64)
+            .map(|i| timed_segment(50, 90_000 + i * 180_000, 3600))
+            .collect()
+    }
+
+    fn check(segments: &[Vec<u8>], durations: &[f64]) -> IntegrityReport {
+        let data = segments.concat();
+        let sizes: Vec<u64> = segments.iter().map(|s| s.len() as u64).collect();
+        check_stream(&data[..], data.len() as u64, durations, &sizes).unwrap()
+    }
+
+    #[test]
+    fn passes_complete_output() {
+        let report = check(&segments(), &[2.0; 4]);
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.segments_found, 4);
+    }
+
+    #[test]
+    fn fails_out_of_order_segments() {
+        let mut segments = segments();
+        segments.swap(1, 2);
+        let report = check(&segments, &[2.0; 4]);
+        assert!(!report.passed());
+        assert!(report.issues.iter().any(|i| i.contains("out of order")));
+    }
+
+    #[test]
+    fn fails_short_output() {
+        let segments = segments();
+        let report = check(&segments[..2], &[2.0; 4]);
+        assert!(!report.passed());
+        assert!(report.issues.iter().any(|i| i.contains("duration")));
+    }
+
+    #[test]
+    fn handles_timestamp_rollover() {
+        let segments: Vec<Vec<u8>> = (0..2u64)
+            .map(|i| {
+                let pts = (TIMESTAMP_WRAP - 90_000 + i * 180_000) % TIMESTAMP_WRAP;
+                timed_segment(50, pts, 3600)
+            })
+            .collect();
+        let report = check(&segments, &[2.0; 2]);
+        assert!(report.passed(), "{}", report);
+    }
+}
@@ -0,0 +1,502 @@
+use anyhow::Context;
+
+// a small parser for javascript literals, enough to read the data blobs embedded in pages
+// it understands objects, arrays, strings (all three quote styles), numbers, booleans,
+// null/undefined and comments. anything else that looks like an identifier is kept verbatim
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsValue {
+    Null,
+    Undefined,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsValue>),
+    Object(Vec<(String, JsValue)>),
+    Identifier(String),
+}
+
+impl JsValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsValue::Number(n) => Some(*n),
+            JsValue::String(s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[JsValue]> {
+        match self {
+            JsValue::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, JsValue)]> {
+        match self {
+            JsValue::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+
+    // first field with the given key, objects keep their source order
+    pub fn get(&self, key: &str) -> Option<&JsValue> {
+        self.as_object()?
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    // depth first search for a key, used when the field we want may be nested
+    // e.g. `{ sources: [{ src: "..." }] }`
+    pub fn find(&self, key: &str) -> Option<&JsValue> {
+        match self {
+            JsValue::Object(o) => o
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v)
+                .or_else(|| o.iter().find_map(|(_, v)| v.find(key))),
+            JsValue::Array(a) => a.iter().find_map(|v| v.find(key)),
+            _ => None,
+        }
+    }
+}
+
+// parses the first literal found in input, returning it along with the number of bytes consumed
+pub fn parse_prefix(input: &str) -> anyhow::Result<(JsValue, usize)> {
+    let mut parser = Parser { src: input, pos: 0 };
+    let value = parser.value()?;
+    Ok((value, parser.pos))
+}
+
+pub fn parse(input: &str) -> anyhow::Result<JsValue> {
+    let mut parser = Parser { src: input, pos: 0 };
+    let value = parser.value()?;
+    parser.skip_trivia()?;
+
+    // allow a statement terminator after the literal
+    if parser.peek() == Some(';') {
+        parser.pos += 1;
+        parser.skip_trivia()?;
+    }
+
+    if parser.pos != input.len() {
+        anyhow::bail!("trailing content after literal at byte [{}]", parser.pos);
+    }
+
+    Ok(value)
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize, // byte offset into src
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> anyhow::Result<()> {
+        match self.bump() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => anyhow::bail!(
+                "expected [{}] but found [{}] at byte [{}]",
+                expected,
+                c,
+                self.pos - c.len_utf8()
+            ),
+            None => anyhow::bail!("expected [{}] but found end of input", expected),
+        }
+    }
+
+    // whitespace and comments
+    fn skip_trivia(&mut self) -> anyhow::Result<()> {
+        loop {
+            let rest = self.rest();
+            if rest.starts_with("//") {
+                self.pos += rest.find('\n').unwrap_or(rest.len());
+            } else if let Some(comment) = rest.strip_prefix("/*") {
+                let end = comment
+                    .find("*/")
+                    .context(format!("unterminated block comment at byte [{}]", self.pos))?;
+                self.pos += end + 4;
+            } else if let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+                self.pos += c.len_utf8();
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn value(&mut self) -> anyhow::Result<JsValue> {
+        self.skip_trivia()?;
+
+        match self
+            .peek()
+            .context("unexpected end of input, expected a value")?
+        {
+            '{' => self.object(),
+            '[' => self.array(),
+            '"' | '\'' | '`' => Ok(JsValue::String(self.string()?)),
+            c if c == '-' || c == '+' || c == '.' || c.is_ascii_digit() => self.number(),
+            c if is_ident_start(c) => {
+                let ident = self.identifier_path();
+                Ok(match ident.as_str() {
+                    "true" => JsValue::Bool(true),
+                    "false" => JsValue::Bool(false),
+                    "null" => JsValue::Null,
+                    "undefined" => JsValue::Undefined,
+                    "NaN" => JsValue::Number(f64::NAN),
+                    "Infinity" => JsValue::Number(f64::INFINITY),
+                    _ => JsValue::Identifier(ident),
+                })
+            }
+            c => anyhow::bail!("unexpected character [{}] at byte [{}]", c, self.pos),
+        }
+    }
+
+    fn object(&mut self) -> anyhow::Result<JsValue> {
+        self.expect('{')?;
+        let mut fields = vec![];
+
+        loop {
+            self.skip_trivia()?;
+            if self.peek() == Some('}') {
+                self.pos += 1;
+                break;
+            }
+
+            let key = match self.peek() {
+                Some('"') | Some('\'') | Some('`') => self.string()?,
+                Some(c) if is_ident_start(c) || c.is_ascii_digit() => self.identifier(),
+                Some(c) => anyhow::bail!("invalid object key start [{}] at byte [{}]", c, self.pos),
+                None => anyhow::bail!("unexpected end of input inside object"),
+            };
+
+            self.skip_trivia()?;
+            self.expect(':')?;
+            let value = self.value()?;
+            fields.push((key, value));
+
+            self.skip_trivia()?;
+            match self.bump() {
+                Some(',') => continue,
+                Some('}') => break,
+                Some(c) => anyhow::bail!("expected [,] or [}}] but found [{}]", c),
+                None => anyhow::bail!("unexpected end of input inside object"),
+            }
+        }
+
+        Ok(JsValue::Object(fields))
+    }
+
+    fn array(&mut self) -> anyhow::Result<JsValue> {
+        self.expect('[')?;
+        let mut items = vec![];
+
+        loop {
+            self.skip_trivia()?;
+            if self.peek() == Some(']') {
+                self.pos += 1;
+                break;
+            }
+
+            items.push(self.value()?);
+
+            self.skip_trivia()?;
+            match self.bump() {
+                Some(',') => continue,
+                Some(']') => break,
+                Some(c) => anyhow::bail!("expected [,] or []] but found [{}]", c),
+                None => anyhow::bail!("unexpected end of input inside array"),
+            }
+        }
+
+        Ok(JsValue::Array(items))
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let start = self.pos;
+        let quote = self.bump().context("expected a string")?;
+        let mut out = String::new();
+
+        loop {
+            let c = self
+                .bump()
+                .context(format!("unterminated string starting at byte [{}]", start))?;
+
+            match c {
+                _ if c == quote => break,
+                '\\' => {
+                    let escaped = self
+                        .bump()
+                        .context(format!("unterminated escape in string at byte [{}]", start))?;
+                    match escaped {
+                        'n' => out.push('\n'),
+                        't' => out.push('\t'),
+                        'r' => out.push('\r'),
+                        'b' => out.push('\u{8}'),
+                        'f' => out.push('\u{c}'),
+                        'v' => out.push('\u{b}'),
+                        '0' => out.push('\0'),
+                        'x' => out.push(self.hex_escape(2)?),
+                        'u' => out.push(self.unicode_escape()?),
+                        // line continuation
+                        '\r' => {
+                            if self.peek() == Some('\n') {
+                                self.pos += 1;
+                            }
+                        }
+                        '\n' | '\u{2028}' | '\u{2029}' => {}
+                        other => out.push(other),
+                    }
+                }
+                '\n' if quote != '`' => {
+                    anyhow::bail!("newline in string literal starting at byte [{}]", start)
+                }
+                _ => out.push(c),
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn hex_escape(&mut self, len: usize) -> anyhow::Result<char> {
+        let digits = self.rest().get(..len).context("truncated hex escape")?;
+        let code =
+            u32::from_str_radix(digits, 16).context(format!("invalid hex escape [{}]", digits))?;
+        self.pos += len;
+        std::char::from_u32(code).context(format!("invalid code point [{:x}]", code))
+    }
+
+    fn unicode_escape(&mut self) -> anyhow::Result<char> {
+        if self.peek() == Some('{') {
+            let end = self
+                .rest()
+                .find('}')
+                .context("unterminated unicode escape")?;
+            let digits = &self.rest()[1..end];
+            let code = u32::from_str_radix(digits, 16)
+                .context(format!("invalid unicode escape [{}]", digits))?;
+            self.pos += end + 1;
+            return std::char::from_u32(code).context(format!("invalid code point [{:x}]", code));
+        }
+
+        let high = self.hex_escape_u16()?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return std::char::from_u32(high).context(format!("invalid code point [{:x}]", high));
+        }
+
+        // surrogate pair, the low half must follow as another escape
+        if !self.rest().starts_with("\\u") {
+            anyhow::bail!("unpaired surrogate [{:x}] in string", high);
+        }
+        self.pos += 2;
+        let low = self.hex_escape_u16()?;
+        if !(0xDC00..=0xDFFF).contains(&low) {
+            anyhow::bail!("invalid low surrogate [{:x}] after [{:x}]", low, high);
+        }
+        let code = 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00);
+        std::char::from_u32(code).context(format!("invalid surrogate pair [{:x}]", code))
+    }
+
+    fn hex_escape_u16(&mut self) -> anyhow::Result<u32> {
+        let digits = self.rest().get(..4).context("truncated unicode escape")?;
+        let code = u32::from_str_radix(digits, 16)
+            .context(format!("invalid unicode escape [{}]", digits))?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn number(&mut self) -> anyhow::Result<JsValue> {
+        let start = self.pos;
+        let negative = match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                true
+            }
+            Some('+') => {
+                self.pos += 1;
+                false
+            }
+            _ => false,
+        };
+
+        let digits_start = self.pos;
+        let is_radix = self.rest().len() > 1
+            && self.rest().starts_with('0')
+            && matches!(
+                self.rest().as_bytes()[1],
+                b'x' | b'X' | b'o' | b'O' | b'b' | b'B'
+            );
+
+        let mut prev = '\0';
+        while let Some(c) = self.peek() {
+            let exponent_sign = !is_radix && (c == '-' || c == '+') && (prev == 'e' || prev == 'E');
+            if !(c.is_ascii_alphanumeric() || c == '.' || c == '_' || exponent_sign) {
+                break;
+            }
+            prev = c;
+            self.pos += 1;
+        }
+
+        let digits = self.src[digits_start..self.pos].replace('_', "");
+        let value = if is_radix {
+            let radix = match digits.as_bytes()[1] {
+                b'x' | b'X' => 16,
+                b'o' | b'O' => 8,
+                _ => 2,
+            };
+            u64::from_str_radix(&digits[2..], radix)
+                .ok()
+                .map(|v| v as f64)
+        } else {
+            digits.parse::<f64>().ok()
+        }
+        .context(format!(
+            "invalid number literal [{}] at byte [{}]",
+            &self.src[start..self.pos],
+            start
+        ))?;
+
+        Ok(JsValue::Number(if negative { -value } else { value }))
+    }
+
+    fn identifier(&mut self) -> String {
+        let rest = self.rest();
+        let len = rest.find(|c: char| !is_ident_part(c)).unwrap_or(rest.len());
+        self.pos += len;
+        rest[..len].into()
+    }
+
+    // identifiers may be member expressions like `window.config.videos`
+    fn identifier_path(&mut self) -> String {
+        let mut path = self.identifier();
+        while self.peek() == Some('.')
+            && self.rest()[1..].chars().next().is_some_and(is_ident_start)
+        {
+            self.pos += 1;
+            path.push('.');
+            path.push_str(&self.identifier());
+        }
+        path
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c == '_' || c == '$' || c.is_alphabetic()
+}
+
+fn is_ident_part(c: char) -> bool {
+    is_ident_start(c) || c.is_alphanumeric()
+}
+
+#[cfg(test)]
+mod jsliteral_tests {
+    use super::*;
+
+    #[test]
+    fn parses_scalars() {
+        assert_eq!(parse("true").unwrap(), JsValue::Bool(true));
+        assert_eq!(parse("null").unwrap(), JsValue::Null);
+        assert_eq!(parse("-12.5").unwrap(), JsValue::Number(-12.5));
+        assert_eq!(parse("0x1F").unwrap(), JsValue::Number(31.0));
+        assert_eq!(parse("1e3").unwrap(), JsValue::Number(1000.0));
+        assert_eq!(parse("'hi'").unwrap(), JsValue::String("hi".into()));
+    }
+
+    #[test]
+    fn parses_string_escapes() {
+        let result = parse(r#""a\"b\\c\nA\x42\u{1F600}""#).unwrap();
+        assert_eq!(result, JsValue::String("a\"b\\c\nAB\u{1F600}".into()));
+
+        let result = parse(r#"'it\'s'"#).unwrap();
+        assert_eq!(result, JsValue::String("it's".into()));
+
+        let result = parse(r#""😀""#).unwrap();
+        assert_eq!(result, JsValue::String("\u{1F600}".into()));
+
+        let result = parse(r#""\ud83d\ude00""#).unwrap();
+        assert_eq!(result, JsValue::String("\u{1F600}".into()));
+        assert!(parse(r#""\ud83d\u0041""#).is_err());
+    }
+
+    #[test]
+    fn brackets_and_comments_inside_strings_are_ignored() {
+        let result = parse(r#"["a]b", '// not a comment', `/* nor this */`]"#).unwrap();
+        assert_eq!(
+            result,
+            JsValue::Array(vec![
+                JsValue::String("a]b".into()),
+                JsValue::String("// not a comment".into()),
+                JsValue::String("/* nor this */".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_objects_with_mixed_keys_comments_and_trailing_commas() {
+        let input = r#"{
+            // line comment
+            src: "https://example.com/a.m3u8", /* block */
+            'title': "Part 1",
+            "duration": 12.5,
+            poster: null,
+            other: some.thing,
+        }"#;
+        let result = parse(input).unwrap();
+
+        assert_eq!(
+            result.get("src").and_then(JsValue::as_str),
+            Some("https://example.com/a.m3u8")
+        );
+        assert_eq!(
+            result.get("title").and_then(JsValue::as_str),
+            Some("Part 1")
+        );
+        assert_eq!(result.get("duration").and_then(JsValue::as_f64), Some(12.5));
+        assert_eq!(result.get("poster"), Some(&JsValue::Null));
+        assert_eq!(
+            result.get("other"),
+            Some(&JsValue::Identifier("some.thing".into()))
+        );
+    }
+
+    #[test]
+    fn find_searches_nested_values() {
+        let result = parse(r#"{ sources: [{ type: "hls", src: "x" }] }"#).unwrap();
+        assert_eq!(result.find("src").and_then(JsValue::as_str), Some("x"));
+    }
+
+    #[test]
+    fn parse_prefix_reports_consumed_bytes() {
+        let input = "[1, 2, 'ü]'] ; var next = 3;";
+        let (value, used) = parse_prefix(input).unwrap();
+        assert_eq!(value.as_array().map(|a| a.len()), Some(3));
+        assert_eq!(&input[..used], "[1, 2, 'ü]']");
+    }
+
+    #[test]
+    fn will_fail_on_unterminated_input() {
+        assert!(parse("[1, 2").is_err());
+        assert!(parse("{a: 'b}").is_err());
+        assert!(parse("'abc").is_err());
+    }
+}
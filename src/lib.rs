@@ -1,4 +1,21 @@
 pub mod mp3url;
 pub mod util;
 pub mod decryption;
-pub mod schoolism;
\ No newline at end of file
+pub mod schoolism;
+pub mod adaptive;
+pub mod archive;
+pub mod cancel;
+pub mod download;
+pub mod hook;
+pub mod http;
+pub mod integrity;
+pub mod jsliteral;
+pub mod metadata;
+pub mod output;
+pub mod queue;
+pub mod ratelimit;
+pub mod resume;
+pub mod server;
+pub mod subtitles;
+pub mod ts;
+pub mod watch;
@@ -1,120 +1,1402 @@
 use anyhow::Context;
 use futures::stream::StreamExt;
-use selene;
+use selene::archive::DownloadArchive;
+use selene::cancel::CancellationToken;
+use selene::download::{Cancelled, ConnectionPool, DownloadEvent, Downloader, EventCallback};
+use selene::http::{HttpConfig, IpVersion};
+use selene::output::{OutputAction, OverwritePolicy, PartFile};
+use selene::queue::{JobQueue, JobStatus};
+use selene::ratelimit::RateLimiter;
+use selene::resume::ResumeState;
 use selene::schoolism::client;
+use selene::schoolism::pacing::Pacing;
+use selene::schoolism::refresh::RefreshablePart;
+use selene::server::{ControlState, JobProgress};
+use selene::watch::SeenParts;
 use std::{
-    io::{BufReader, Seek, SeekFrom},
-    sync::Arc,
+    collections::{BTreeSet, HashMap, HashSet},
+    io::BufWriter,
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Arc, Mutex},
 };
 
 use clap::Clap;
-use std::fs::File;
-use tokio::fs::File as TokioFile;
-use tokio::io::copy;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Clap)]
 #[clap(version = "1.0", author = "dtn", about = "selene")]
 struct Opts {
+    #[clap(subcommand)]
+    command: Option<Command>,
     #[clap(short, long)]
-    username: String,
+    username: Option<String>,
     #[clap(short, long)]
-    password: String,
-    #[clap(long, about = "Index of lesson to download")]
-    lesson: usize,
-    #[clap(long, about = "Index of part to download")]
-    part: usize,
+    password: Option<String>,
+    #[clap(long, about = "Index of lesson to download, may be repeated")]
+    lesson: Vec<usize>,
+    #[clap(
+        long,
+        about = "Index of part to download from every lesson, may be repeated"
+    )]
+    part: Vec<usize>,
     #[clap(long, default_value = "4", about = "Parallel downloads allowed")]
     parallel: usize,
+    #[clap(long, default_value = "1", about = "Parts downloaded at the same time")]
+    parallel_parts: usize,
+    #[clap(
+        long,
+        about = "Segment downloads allowed across all parts, defaults to --parallel"
+    )]
+    connections: Option<usize>,
+    #[clap(
+        long,
+        about = "Adjust parallel downloads to the observed throughput and server errors, up to --parallel"
+    )]
+    adaptive: bool,
+    #[clap(
+        long,
+        default_value = "3",
+        about = "Times a failed segment is retried before giving up"
+    )]
+    retries: u32,
     #[clap(long, about = "Request high quality video")]
     hq: bool,
+    #[clap(long, about = "Download available subtitles next to the video")]
+    subs: bool,
+    #[clap(long, about = "Convert downloaded subtitles to SRT")]
+    srt: bool,
+    #[clap(
+        long,
+        about = "Download lesson attachments (briefs, PDFs, reference images)"
+    )]
+    attachments: bool,
+    #[clap(short, long, default_value = ".", about = "Directory to save files to")]
+    output_dir: PathBuf,
+    #[clap(long, about = "Write a JSON metadata sidecar next to the video")]
+    write_info_json: bool,
+    #[clap(
+        long,
+        about = "Write a Kodi style NFO metadata sidecar next to the video"
+    )]
+    write_nfo: bool,
+    #[clap(
+        long,
+        about = "Record downloaded parts in this file and skip parts already listed in it"
+    )]
+    download_archive: Option<PathBuf>,
+    #[clap(
+        long,
+        about = "Maximum download rate across all segments, in bytes per second (e.g. 500K, 2M)"
+    )]
+    limit_rate: Option<String>,
+    #[clap(long, about = "Maximum page, playlist and key requests per second")]
+    limit_requests: Option<f64>,
+    #[clap(
+        long,
+        default_value = "1.0",
+        about = "Minimum delay between page navigation requests, in seconds"
+    )]
+    min_request_delay: f64,
+    #[clap(
+        long,
+        about = "Maximum delay between page navigation requests, in seconds, randomized between min and max"
+    )]
+    max_request_delay: Option<f64>,
+    #[clap(long, about = "HTTP, HTTPS or SOCKS5 proxy url for all requests")]
+    proxy: Option<String>,
+    #[clap(long, about = "Connection timeout, in seconds")]
+    connect_timeout: Option<f64>,
+    #[clap(
+        long,
+        about = "Timeout for a whole request including the response body, in seconds"
+    )]
+    timeout: Option<f64>,
+    #[clap(long, about = "User agent sent with every request")]
+    user_agent: Option<String>,
+    #[clap(
+        long = "header",
+        about = "Extra header sent with every request, as [name: value], may be repeated"
+    )]
+    headers: Vec<String>,
+    #[clap(
+        long = "ca-cert",
+        about = "Additional trusted root certificate (PEM or DER), may be repeated"
+    )]
+    ca_certs: Vec<PathBuf>,
+    #[clap(
+        long,
+        about = "Don't check that decrypted segments are valid MPEG transport streams"
+    )]
+    no_verify_segments: bool,
+    #[clap(
+        long,
+        about = "Fail a part whose merged output doesn't pass the integrity check, instead of only reporting it"
+    )]
+    strict_integrity: bool,
+    #[clap(long, about = "Replace existing output files (default)")]
+    overwrite: bool,
+    #[clap(long, about = "Fail if the output file already exists")]
+    no_overwrite: bool,
+    #[clap(long, about = "Do nothing if the output file already exists")]
+    skip_existing: bool,
+    #[clap(
+        long,
+        about = "Delete the partial output when interrupted instead of keeping it to resume"
+    )]
+    delete_partial: bool,
+    #[clap(
+        long,
+        about = "Shell command run after each part is saved, {path}, {title}, {lesson}, {part}, {lesson_title}, {course}, {info_json} and {output_dir} are replaced with quoted values, and set as SELENE_<NAME> environment variables"
+    )]
+    exec: Option<String>,
+    #[clap(
+        long,
+        about = "Shell command run as soon as the parts of a --lesson are done, with {lesson}, {lesson_title}, {output_dir}, {downloaded} and {failed}, not used by the queue, serve and watch subcommands or when interrupted"
+    )]
+    exec_lesson: Option<String>,
+    #[clap(
+        long,
+        about = "Shell command run once all downloads are done, with {output_dir}, {downloaded} and {failed}, not run when interrupted"
+    )]
+    exec_run: Option<String>,
+    #[clap(
+        long,
+        about = "Resolve the requested parts and print what would be downloaded, without downloading anything"
+    )]
+    simulate: bool,
+    #[clap(
+        long,
+        about = "Print the resolved stream of each requested part as a line of JSON, without downloading anything"
+    )]
+    dump_json: bool,
+    #[clap(short = '4', long, about = "Only connect over IPv4")]
+    force_ipv4: bool,
+    #[clap(short = '6', long, about = "Only connect over IPv6")]
+    force_ipv6: bool,
+}
+
+#[derive(Clap)]
+enum Command {
+    #[clap(about = "Manage and process a persistent download queue")]
+    Queue(QueueOpts),
+    #[clap(about = "Stay logged in and serve a local HTTP/JSON API for browsing and downloading")]
+    Serve(ServeOpts),
+    #[clap(about = "Poll the dashboard and download newly unlocked lessons")]
+    Watch(WatchOpts),
+}
+
+#[derive(Clap)]
+struct WatchOpts {
+    #[clap(
+        long,
+        default_value = "selene-watch.json",
+        about = "File recording the parts already seen"
+    )]
+    state: PathBuf,
+    #[clap(
+        long,
+        default_value = "3600",
+        about = "Seconds between dashboard checks"
+    )]
+    interval: u64,
+    #[clap(
+        long,
+        about = "Shell command run after each new part, with {status}, {lesson}, {part}, {lesson_title}, {part_title} and {error}"
+    )]
+    notify: Option<String>,
+    #[clap(
+        long,
+        about = "Record everything currently available as seen without downloading it"
+    )]
+    baseline: bool,
+    #[clap(long, about = "Check once and exit")]
+    once: bool,
+}
+
+#[derive(Clap)]
+struct ServeOpts {
+    #[clap(
+        long,
+        default_value = "127.0.0.1:8631",
+        about = "Loopback address the API listens on"
+    )]
+    listen: SocketAddr,
+    #[clap(long, default_value = "selene-queue.json", about = "Queue file")]
+    file: PathBuf,
+    #[clap(
+        long,
+        default_value = "3",
+        about = "Runs a failing job gets before it's marked failed"
+    )]
+    max_attempts: u32,
+}
+
+#[derive(Clap)]
+struct QueueOpts {
+    #[clap(long, default_value = "selene-queue.json", about = "Queue file")]
+    file: PathBuf,
+    #[clap(subcommand)]
+    action: QueueAction,
+}
+
+#[derive(Clap)]
+enum QueueAction {
+    #[clap(about = "Queue parts of a lesson")]
+    Add {
+        #[clap(long)]
+        lesson: usize,
+        #[clap(long, required = true, about = "May be repeated")]
+        part: Vec<usize>,
+        #[clap(long, about = "Request high quality video")]
+        hq: bool,
+    },
+    #[clap(about = "Show queued jobs and their status")]
+    List,
+    #[clap(about = "Download pending jobs until the queue is empty")]
+    Run {
+        #[clap(
+            long,
+            default_value = "3",
+            about = "Runs a failing job gets before it's marked failed"
+        )]
+        max_attempts: u32,
+    },
+    #[clap(about = "Queue failed jobs, or the given job, again")]
+    Retry { id: Option<u64> },
+    #[clap(about = "Remove a job from the queue")]
+    Remove { id: u64 },
+}
+
+impl Opts {
+    fn overwrite_policy(&self) -> anyhow::Result<OverwritePolicy> {
+        match (self.overwrite, self.no_overwrite, self.skip_existing) {
+            (_, false, false) => Ok(OverwritePolicy::Overwrite),
+            (false, true, false) => Ok(OverwritePolicy::NoOverwrite),
+            (false, false, true) => Ok(OverwritePolicy::SkipExisting),
+            _ => anyhow::bail!(
+                "only one of --overwrite, --no-overwrite and --skip-existing may be set"
+            ),
+        }
+    }
+
+    fn http_config(&self) -> anyhow::Result<HttpConfig> {
+        let ip_version = match (self.force_ipv4, self.force_ipv6) {
+            (true, true) => anyhow::bail!("only one of --force-ipv4 and --force-ipv6 may be set"),
+            (true, false) => IpVersion::V4,
+            (false, true) => IpVersion::V6,
+            (false, false) => IpVersion::Any,
+        };
+
+        let mut config = HttpConfig {
+            proxy: self.proxy.clone(),
+            connect_timeout: self.connect_timeout.map(Duration::from_secs_f64),
+            timeout: self.timeout.map(Duration::from_secs_f64),
+            root_certificates: self.ca_certs.clone(),
+            ip_version,
+            ..HttpConfig::default()
+        };
+        if let Some(user_agent) = &self.user_agent {
+            config.user_agent = user_agent.clone();
+        }
+        for header in &self.headers {
+            config.add_header(header)?;
+        }
+
+        Ok(config)
+    }
+}
+
+// state shared by every part being downloaded
+struct Session {
+    opts: Opts,
+    client: Arc<client::ClientConnected>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    pool: Arc<ConnectionPool>,
+    cancellation: CancellationToken,
+    abort: CancellationToken,
+    archive: Mutex<Option<DownloadArchive>>,
+    lesson_titles: Mutex<HashMap<usize, String>>, // as resolved by the parts downloaded so far
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let opts: Opts = Opts::parse();
-
-    // TODO get lesson name from scraping
-    let file_out_name = {
-        let quality = if opts.hq { "hq" } else { "nq" };
-        format!(
-            "selene_lesson_{}_part_{}_{}.mp4",
-            opts.lesson, opts.part, quality
+    let mut opts: Opts = Opts::parse();
+    opts.overwrite_policy()?;
+    if (opts.simulate || opts.dump_json) && opts.command.is_some() {
+        anyhow::bail!(
+            "--simulate and --dump-json only apply to parts given with --lesson and --part"
+        );
+    }
+    if opts.simulate && opts.dump_json {
+        anyhow::bail!("only one of --simulate and --dump-json may be set");
+    }
+
+    match opts.command.take() {
+        Some(Command::Queue(queue_opts)) => run_queue_command(opts, queue_opts).await,
+        Some(Command::Serve(serve_opts)) => serve(opts, serve_opts).await,
+        Some(Command::Watch(watch_opts)) => watch(opts, watch_opts).await,
+        None => download_requested(opts).await,
+    }
+}
+
+// downloads every --part of every --lesson given on the command line
+async fn download_requested(opts: Opts) -> anyhow::Result<()> {
+    if opts.lesson.is_empty() || opts.part.is_empty() {
+        anyhow::bail!("--lesson and --part are required unless a subcommand is given");
+    }
+
+    // repeated lessons or parts would download the same file twice, concurrently
+    let lessons: BTreeSet<usize> = opts.lesson.iter().copied().collect();
+    let parts: BTreeSet<usize> = opts.part.iter().copied().collect();
+    let jobs: Vec<(usize, usize)> = lessons
+        .iter()
+        .flat_map(|&lesson| parts.iter().map(move |&part| (lesson, part)))
+        .collect();
+
+    let session = connect(opts).await?;
+    let hq = session.opts.hq;
+
+    if session.opts.simulate {
+        return simulate(&session, &lessons, &jobs).await;
+    }
+    if session.opts.dump_json {
+        return dump_json(&session, &jobs).await;
+    }
+
+    // parts left and failed per lesson, whichever part finishes a lesson also wraps it up
+    let lesson_parts: Mutex<HashMap<usize, (usize, usize)>> = Mutex::new(
+        lessons
+            .iter()
+            .map(|&lesson| (lesson, (parts.len(), 0)))
+            .collect(),
+    );
+    let attachments_failed = AtomicUsize::new(0);
+
+    // parts run side by side, their segments interleave in the shared connection pool
+    // parts that haven't started by the time of a ctrl-c aren't started at all
+    let results = futures::stream::iter(jobs.iter().copied())
+        .take_while(|_| futures::future::ready(!session.cancellation.is_cancelled()))
+        .map(|(lesson, part)| {
+            let session = &session;
+            let lesson_parts = &lesson_parts;
+            let attachments_failed = &attachments_failed;
+            let lesson_size = parts.len();
+            async move {
+                let result =
+                    download_part(session, lesson, part, hq, &session.cancellation, None).await;
+                if let Err(e) = &result {
+                    println!("lesson [{}] part [{}] failed: {:#}", lesson, part, e);
+                }
+
+                let lesson_done = {
+                    let mut lesson_parts = lesson_parts.lock().unwrap();
+                    let (left, failed) = lesson_parts.get_mut(&lesson).unwrap();
+                    *left -= 1;
+                    *failed += result.is_err() as usize;
+                    Some(*failed).filter(|_| *left == 0)
+                };
+                if let Some(failed) = lesson_done {
+                    if !finish_lesson(session, lesson, lesson_size - failed, failed).await {
+                        attachments_failed.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+                result
+            }
+        })
+        .buffer_unordered(session.opts.parallel_parts.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    let failed = results.iter().filter(|result| result.is_err()).count();
+
+    // counts of an interrupted run don't describe it, so the hooks don't run
+    if let Some(command) = &session.opts.exec_run {
+        if !session.cancellation.is_cancelled() {
+            run_hook(
+                command,
+                &run_values(&session.opts, results.len() - failed, failed),
+            )
+            .await;
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("[{}] of [{}] parts failed", failed, jobs.len());
+    }
+    let attachments_failed = attachments_failed.into_inner();
+    if attachments_failed > 0 {
+        anyhow::bail!("attachments of [{}] lessons failed", attachments_failed);
+    }
+    Ok(())
+}
+
+// downloads the attachments of a --lesson once its parts are done and runs --exec-lesson,
+// neither happens after ctrl-c, returns false if the attachments failed
+async fn finish_lesson(session: &Session, lesson: usize, downloaded: usize, failed: usize) -> bool {
+    let mut attachments_ok = true;
+    if session.opts.attachments && !session.cancellation.is_cancelled() {
+        if let Err(e) = download_attachments(session, lesson).await {
+            println!("lesson [{}] attachments failed: {:#}", lesson, e);
+            attachments_ok = false;
+        }
+    }
+
+    if let Some(command) = &session.opts.exec_lesson {
+        if !session.cancellation.is_cancelled() {
+            let lesson_title = session.lesson_titles.lock().unwrap().get(&lesson).cloned();
+            let values = [
+                ("lesson", lesson.to_string()),
+                ("lesson_title", lesson_title.unwrap_or_default()),
+                ("output_dir", session.opts.output_dir.display().to_string()),
+                ("downloaded", downloaded.to_string()),
+                ("failed", failed.to_string()),
+            ];
+            run_hook(command, &values).await;
+        }
+    }
+    attachments_ok
+}
+
+async fn run_queue_command(opts: Opts, queue_opts: QueueOpts) -> anyhow::Result<()> {
+    let queue = JobQueue::new(&queue_opts.file);
+
+    match queue_opts.action {
+        QueueAction::Add { lesson, part, hq } => {
+            for part in part {
+                let id = queue.add(lesson, part, hq)?;
+                println!("queued lesson [{}] part [{}] as job [{}]", lesson, part, id);
+            }
+        }
+        QueueAction::List => {
+            let jobs = queue.jobs()?;
+            for job in &jobs {
+                println!(
+                    "[{}] lesson [{}] part [{}] {} {} (attempts [{}]){}",
+                    job.id,
+                    job.lesson,
+                    job.part,
+                    if job.hq { "hq" } else { "nq" },
+                    job.status,
+                    job.attempts,
+                    job.last_error
+                        .as_ref()
+                        .map(|e| format!(": {}", e))
+                        .unwrap_or_default()
+                );
+            }
+            if jobs.is_empty() {
+                println!("queue is empty");
+            }
+        }
+        QueueAction::Retry { id } => {
+            let count = queue.retry(id)?;
+            println!("queued [{}] jobs again", count);
+        }
+        QueueAction::Remove { id } => {
+            if !queue.remove(id)? {
+                anyhow::bail!("no job [{}] in the queue", id);
+            }
+            println!("removed job [{}]", id);
+        }
+        QueueAction::Run { max_attempts } => {
+            let session = connect(opts).await?;
+            run_queue(&session, &queue, max_attempts).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// works through pending jobs one at a time, picking up jobs added while it runs
+async fn run_queue(session: &Session, queue: &JobQueue, max_attempts: u32) -> anyhow::Result<()> {
+    let worker = queue.lock_worker()?;
+    let stale = queue.reset_running(&worker)?;
+    if stale > 0 {
+        println!("[{}] interrupted jobs queued again", stale);
+    }
+
+    let mut attachments_done = HashSet::new();
+    let (mut done, mut failed) = (0, 0);
+    while !session.cancellation.is_cancelled() {
+        let job = match queue.claim_next()? {
+            Some(job) => job,
+            None => break,
+        };
+        println!(
+            "job [{}]: lesson [{}] part [{}], attempt [{}] of [{}]",
+            job.id, job.lesson, job.part, job.attempts, max_attempts
+        );
+
+        let result = download_part(
+            session,
+            job.lesson,
+            job.part,
+            job.hq,
+            &session.cancellation,
+            None,
+        )
+        .await;
+        if result.is_err() && session.cancellation.is_cancelled() {
+            // interrupted, the part resumes on the next run
+            queue.release(job.id)?;
+            break;
+        }
+
+        if result.is_ok() && session.opts.attachments && attachments_done.insert(job.lesson) {
+            if let Err(e) = download_attachments(session, job.lesson).await {
+                println!("lesson [{}] attachments failed: {:#}", job.lesson, e);
+            }
+        }
+
+        let status = queue.finish(job.id, result.map_err(|e| format!("{:#}", e)), max_attempts)?;
+        println!("job [{}]: {}", job.id, status);
+        match status {
+            JobStatus::Done => done += 1,
+            JobStatus::Failed => failed += 1,
+            _ => {}
+        }
+    }
+
+    println!("queue run finished, [{}] done, [{}] failed", done, failed);
+    if let Some(command) = &session.opts.exec_run {
+        if !session.cancellation.is_cancelled() {
+            run_hook(command, &run_values(&session.opts, done, failed)).await;
+        }
+    }
+    Ok(())
+}
+
+fn run_values(opts: &Opts, downloaded: usize, failed: usize) -> [(&'static str, String); 3] {
+    [
+        ("output_dir", opts.output_dir.display().to_string()),
+        ("downloaded", downloaded.to_string()),
+        ("failed", failed.to_string()),
+    ]
+}
+
+// post-processing hooks report failures without failing the download they follow
+async fn run_hook(template: &str, values: &[(&str, String)]) {
+    let command = selene::hook::expand(template, values);
+    if let Err(e) = selene::hook::run(&command, values).await {
+        println!("{:#}", e);
+    }
+}
+
+// keeps the session logged in for the control api, and works through the queue next to it
+async fn serve(opts: Opts, serve_opts: ServeOpts) -> anyhow::Result<()> {
+    // the api has no authentication, anyone who can reach it can use the session
+    if !serve_opts.listen.ip().is_loopback() {
+        anyhow::bail!(
+            "the control api only listens on loopback addresses, got [{}]",
+            serve_opts.listen
+        );
+    }
+
+    let queue = JobQueue::new(&serve_opts.file);
+    let worker_lock = queue.lock_worker()?;
+    let session = connect(opts).await?;
+    let stale = queue.reset_running(&worker_lock)?;
+    if stale > 0 {
+        println!("[{}] interrupted jobs queued again", stale);
+    }
+    let state = Arc::new(ControlState::new(queue));
+
+    let server = selene::server::serve(
+        serve_opts.listen,
+        state.clone(),
+        session.client.clone(),
+        session.cancellation.clone(),
+    );
+    let worker = serve_jobs(&session, &state, serve_opts.max_attempts);
+    let (served, worked) = futures::join!(server, worker);
+    served?;
+    worked
+}
+
+// like `run_queue`, but waits for new jobs instead of stopping when the queue is empty,
+// and lets the api follow and cancel the running job
+async fn serve_jobs(
+    session: &Session,
+    state: &Arc<ControlState>,
+    max_attempts: u32,
+) -> anyhow::Result<()> {
+    let mut attachments_done = HashSet::new();
+
+    while !session.cancellation.is_cancelled() {
+        let (job, cancellation) = match state.claim_next(&session.cancellation).await? {
+            Some(claimed) => claimed,
+            None => {
+                tokio::select! {
+                    _ = state.wait_for_jobs(Duration::from_secs(5)) => {}
+                    _ = session.cancellation.cancelled() => {}
+                }
+                continue;
+            }
+        };
+        println!(
+            "job [{}]: lesson [{}] part [{}], attempt [{}] of [{}]",
+            job.id, job.lesson, job.part, job.attempts, max_attempts
+        );
+
+        let progress: EventCallback = {
+            let state = Arc::clone(state);
+            let id = job.id;
+            Arc::new(move |event: &DownloadEvent| {
+                if let DownloadEvent::Progress {
+                    segments_written,
+                    segments_total,
+                    bytes_written,
+                } = *event
+                {
+                    state.update_progress(
+                        id,
+                        JobProgress {
+                            segments_written,
+                            segments_total,
+                            bytes_written,
+                        },
+                    );
+                }
+            })
+        };
+        let result = download_part(
+            session,
+            job.lesson,
+            job.part,
+            job.hq,
+            &cancellation,
+            Some(progress),
         )
+        .await;
+        state.end_job(job.id);
+
+        if result.is_err() && session.cancellation.is_cancelled() {
+            let id = job.id;
+            state.with_queue(move |_, queue| queue.release(id)).await?;
+            break;
+        }
+        if result.is_err() && cancellation.is_cancelled() {
+            // deleted through the api, which already took it off the queue
+            println!("job [{}]: cancelled", job.id);
+            continue;
+        }
+
+        if result.is_ok() && session.opts.attachments && attachments_done.insert(job.lesson) {
+            if let Err(e) = download_attachments(session, job.lesson).await {
+                println!("lesson [{}] attachments failed: {:#}", job.lesson, e);
+            }
+        }
+
+        let (id, result) = (job.id, result.map_err(|e| format!("{:#}", e)));
+        let status = state
+            .with_queue(move |_, queue| queue.finish(id, result, max_attempts))
+            .await?;
+        println!("job [{}]: {}", job.id, status);
+    }
+
+    Ok(())
+}
+
+// downloads new material as it's unlocked, until interrupted
+async fn watch(opts: Opts, watch_opts: WatchOpts) -> anyhow::Result<()> {
+    let session = connect(opts).await?;
+    let mut seen = SeenParts::load(&watch_opts.state)?;
+    if seen.is_empty() && !watch_opts.baseline {
+        println!("watch: nothing seen yet, every available part will be downloaded");
+    }
+
+    let mut baseline = watch_opts.baseline;
+    loop {
+        if let Err(e) = check_for_new_parts(&session, &watch_opts, &mut seen, baseline).await {
+            println!("watch: check failed, trying again next time: {:#}", e);
+        }
+        seen.save(&watch_opts.state)?;
+        baseline = false;
+
+        if watch_opts.once || session.cancellation.is_cancelled() {
+            break;
+        }
+        println!("watch: next check in [{}] seconds", watch_opts.interval);
+        tokio::select! {
+            _ = tokio::time::delay_for(Duration::from_secs(watch_opts.interval)) => {}
+            _ = session.cancellation.cancelled() => break,
+        }
+    }
+
+    Ok(())
+}
+
+// one pass over the dashboard, downloading every part that hasn't been seen yet
+async fn check_for_new_parts(
+    session: &Session,
+    watch_opts: &WatchOpts,
+    seen: &mut SeenParts,
+    baseline: bool,
+) -> anyhow::Result<()> {
+    let lessons = session
+        .client
+        .get_lessons()
+        .await
+        .context("failed to list lessons")?;
+
+    for (lesson_idx, lesson) in lessons.iter().enumerate() {
+        // one broken lesson page shouldn't keep the others from being checked
+        let page = match session.client.get_lesson_page(lesson).await {
+            Ok(page) => page,
+            Err(e) => {
+                println!("watch: could not check lesson [{}]: {:#}", lesson_idx, e);
+                continue;
+            }
+        };
+
+        for (part_idx, part) in page.parts.iter().enumerate() {
+            let id = lesson.part_id(part, session.opts.hq);
+            if seen.contains(&id) {
+                continue;
+            }
+            if baseline {
+                seen.insert(&id);
+                continue;
+            }
+            if session.cancellation.is_cancelled() {
+                return Ok(());
+            }
+
+            println!(
+                "watch: new part, lesson [{}] part [{}]",
+                lesson_idx, part_idx
+            );
+            let result = download_part(
+                session,
+                lesson_idx,
+                part_idx,
+                session.opts.hq,
+                &session.cancellation,
+                None,
+            )
+            .await;
+            if result.is_err() && session.cancellation.is_cancelled() {
+                return Ok(());
+            }
+
+            let status = match &result {
+                Ok(()) => {
+                    seen.insert(&id);
+                    seen.save(&watch_opts.state)?;
+                    "downloaded"
+                }
+                Err(e) => {
+                    println!(
+                        "lesson [{}] part [{}] failed: {:#}",
+                        lesson_idx, part_idx, e
+                    );
+                    "failed"
+                }
+            };
+
+            if let Some(command) = &watch_opts.notify {
+                let values = [
+                    ("status", status.to_string()),
+                    ("lesson", lesson_idx.to_string()),
+                    ("part", part_idx.to_string()),
+                    ("lesson_title", lesson.title.clone().unwrap_or_default()),
+                    ("part_title", part.title.clone().unwrap_or_default()),
+                    (
+                        "error",
+                        result
+                            .as_ref()
+                            .err()
+                            .map(|e| format!("{:#}", e))
+                            .unwrap_or_default(),
+                    ),
+                ];
+                run_hook(command, &values).await;
+            }
+        }
+    }
+
+    if baseline {
+        println!("watch: recorded current lessons as seen");
+    }
+    Ok(())
+}
+
+// logs in and sets up everything downloads share
+async fn connect(opts: Opts) -> anyhow::Result<Session> {
+    let (username, password) = match (&opts.username, &opts.password) {
+        (Some(username), Some(password)) => (username, password),
+        _ => anyhow::bail!("--username and --password are required to download"),
     };
 
-    println!("saving file to [{}]", file_out_name);
+    if !opts.simulate && !opts.dump_json {
+        std::fs::create_dir_all(&opts.output_dir).context("could not create output directory")?;
+    }
+
+    let rate_limiter = match &opts.limit_rate {
+        Some(rate) => Some(Arc::new(RateLimiter::new(
+            selene::ratelimit::parse_rate(rate)? as f64,
+        ))),
+        None => None,
+    };
+
+    let pacing = {
+        let min = opts.min_request_delay;
+        let max = opts.max_request_delay.unwrap_or(min);
+        if !(min >= 0.0 && max >= min && max.is_finite()) {
+            anyhow::bail!("request delays must satisfy 0 <= min <= max");
+        }
+        Pacing::new(Duration::from_secs_f64(min), Duration::from_secs_f64(max))
+    };
 
     // establish connection
-    let client = client::ClientInit::new(&opts.username, &opts.password)?
-        .connect()
-        .await?;
+    let http_config = opts.http_config()?;
+    let mut client =
+        client::ClientInit::with_config(username, password, &http_config)?.with_pacing(pacing);
+    if let Some(requests) = opts.limit_requests {
+        // also rules out nan and inf, which `RateLimiter` refuses
+        if !(requests.is_finite() && requests > 0.0) {
+            anyhow::bail!(
+                "request limit must be a number greater than zero, got [{}]",
+                requests
+            );
+        }
+        client = client.with_request_limiter(Arc::new(RateLimiter::new(requests)));
+    }
+    let client = Arc::new(client.connect().await?);
 
-    println!("connected to schoolism");
+    // stdout is reserved for the json
+    if !opts.dump_json {
+        println!("connected to schoolism");
+    }
 
-    // get playlist for chosen lesson and part, if it doesn't exist, bail
-    let list = client.get_playlist(opts.lesson, opts.part, opts.hq).await?;
-    let cipher = Arc::new(selene::decryption::Cipher::from_list(&list));
+    let archive = match &opts.download_archive {
+        Some(path) => Some(DownloadArchive::open(path)?),
+        None => None,
+    };
+
+    // the first ctrl-c lets in-flight segments finish so the output can be resumed, a second
+    // one gives up on them but still keeps what was written, a third exits right away
+    let cancellation = CancellationToken::new();
+    let abort = CancellationToken::new();
+    {
+        let cancellation = cancellation.clone();
+        let abort = abort.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            println!("interrupted, finishing in-flight segments, press ctrl-c again to abort");
+            cancellation.cancel();
+
+            let _ = tokio::signal::ctrl_c().await;
+            println!("aborting in-flight segments, press ctrl-c again to exit immediately");
+            abort.cancel();
 
-    println!("retrieved playlist details");
+            let _ = tokio::signal::ctrl_c().await;
+            std::process::exit(130);
+        });
+    }
+
+    Ok(Session {
+        pool: Arc::new(ConnectionPool::new(
+            opts.connections.unwrap_or(opts.parallel),
+        )),
+        opts,
+        client,
+        rate_limiter,
+        cancellation,
+        abort,
+        archive: Mutex::new(archive),
+        lesson_titles: Mutex::new(HashMap::new()),
+    })
+}
+
+// TODO get lesson name from scraping
+fn output_path(opts: &Opts, lesson: usize, part_idx: usize, hq: bool) -> PathBuf {
+    let quality = if hq { "hq" } else { "nq" };
+    opts.output_dir.join(format!(
+        "selene_lesson_{}_part_{}_{}.mp4",
+        lesson, part_idx, quality
+    ))
+}
+
+// attachments often share names across lessons (`brief.pdf`), so each lesson gets its own
+// directory
+fn attachment_path(opts: &Opts, lesson: usize, name: &str) -> PathBuf {
+    opts.output_dir
+        .join(format!("selene_lesson_{}_attachments", lesson))
+        .join(selene::util::sanitize_file_name(name))
+}
+
+// resolves everything a download would use and prints the plan, fetching no segments
+async fn simulate(
+    session: &Session,
+    lessons: &BTreeSet<usize>,
+    jobs: &[(usize, usize)],
+) -> anyhow::Result<()> {
+    let opts = &session.opts;
+    let mut estimated_total = 0;
+    let mut failed = 0;
 
-    let download_client = Arc::new(reqwest::Client::new());
+    for &(lesson, part_idx) in jobs {
+        match simulate_part(session, lesson, part_idx, opts.hq).await {
+            Ok(estimated) => estimated_total += estimated.unwrap_or_default(),
+            Err(e) => {
+                println!("lesson [{}] part [{}] failed: {:#}", lesson, part_idx, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if opts.attachments {
+        for &lesson in lessons {
+            let page = session
+                .client
+                .get_lesson(lesson)
+                .await
+                .context("failed to get lesson attachments")?;
+            for attachment in &page.attachments {
+                let path = attachment_path(opts, lesson, &attachment.name);
+                println!(
+                    "lesson [{}]: attachment [{}] -> [{}]",
+                    lesson,
+                    attachment.url,
+                    path.display()
+                );
+            }
+        }
+    }
 
     println!(
-        "downloading [{}] parts with [{}] threads",
+        "simulated [{}] parts, estimated total [{}], nothing was downloaded",
+        jobs.len(),
+        selene::util::format_size(estimated_total)
+    );
+    if failed > 0 {
+        anyhow::bail!("[{}] of [{}] parts failed", failed, jobs.len());
+    }
+    Ok(())
+}
+
+// one json object per line on stdout, errors go to stderr so the output stays parseable
+async fn dump_json(session: &Session, jobs: &[(usize, usize)]) -> anyhow::Result<()> {
+    let mut failed = 0;
+    for &(lesson, part_idx) in jobs {
+        let info = session
+            .client
+            .get_stream_info(lesson, part_idx, session.opts.hq, session.opts.subs)
+            .await;
+        match info {
+            Ok(info) => println!(
+                "{}",
+                serde_json::to_string(&info).context("could not serialize stream info")?
+            ),
+            Err(e) => {
+                eprintln!("lesson [{}] part [{}] failed: {:#}", lesson, part_idx, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("[{}] of [{}] parts failed", failed, jobs.len());
+    }
+    Ok(())
+}
+
+// returns the estimated size, when the variant declares a bitrate
+async fn simulate_part(
+    session: &Session,
+    lesson: usize,
+    part_idx: usize,
+    hq: bool,
+) -> anyhow::Result<Option<u64>> {
+    let opts = &session.opts;
+    let label = format!("lesson [{}] part [{}]", lesson, part_idx);
+    let list = session
+        .client
+        .get_playlist(lesson, part_idx, hq, opts.subs)
+        .await?;
+    let path = output_path(opts, lesson, part_idx, hq);
+
+    println!(
+        "{}: [{}] / [{}] / [{}]",
+        label,
+        list.course.as_deref().unwrap_or("?"),
+        list.lesson_title.as_deref().unwrap_or("?"),
+        list.part_title.as_deref().unwrap_or("?")
+    );
+    let action = match opts.overwrite_policy()?.check(&path) {
+        Ok(OutputAction::Skip) => "exists, would be skipped",
+        Ok(OutputAction::Write) if path.exists() => "exists, would be replaced",
+        Ok(OutputAction::Write) => "would be written",
+        Err(_) => "exists, would fail",
+    };
+    println!("{}: output [{}] {}", label, path.display(), action);
+    let recorded = session
+        .archive
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|archive| archive.contains(&list.archive_id()));
+    if recorded {
+        println!("{}: already recorded in the archive", label);
+    }
+
+    let mut attribs: Vec<_> = list.variant_attribs.iter().collect();
+    attribs.sort();
+    println!("{}: variant [{}] {:?}", label, list.variant_url, attribs);
+    println!(
+        "{}: AES-128, [{}] byte key, iv [{}]",
+        label,
+        list.key.len(),
+        list.iv
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    );
+    println!(
+        "{}: [{}] segments, [{:.1}] seconds, [{}] subtitle tracks",
+        label,
         list.files.len(),
-        opts.parallel
+        list.total_duration(),
+        list.subtitles.len()
     );
+    for (file, duration) in list.files.iter().zip(&list.durations) {
+        println!("  {} ({:.2}s)", file, duration);
+    }
 
-    // parse playlist into file parts
-    // TODO error handling here
-    let tasks_iter = list.files.into_iter().map(|url| {
-        let cipher = cipher.clone();
-        let download_client = download_client.clone();
+    let estimated = list.estimated_size();
+    match estimated {
+        Some(size) => println!(
+            "{}: estimated size [{}]",
+            label,
+            selene::util::format_size(size)
+        ),
+        None => println!("{}: no BANDWIDTH declared, size unknown", label),
+    }
+    Ok(estimated)
+}
 
-        async move {
-            let start = std::time::Instant::now();
+async fn download_part(
+    session: &Session,
+    lesson: usize,
+    part_idx: usize,
+    hq: bool,
+    cancellation: &CancellationToken,
+    observer: Option<EventCallback>,
+) -> anyhow::Result<()> {
+    let opts = &session.opts;
+    let client = &session.client;
+    let label = format!("lesson [{}] part [{}]", lesson, part_idx);
 
-            // prepare fs
-            let f = tempfile::tempfile().unwrap();
-            let mut f = TokioFile::from_std(f);
+    let file_out_name = output_path(opts, lesson, part_idx, hq);
 
-            // download
-            let resp = download_client.get(&url).send().await.unwrap();
-            let mut bytes = resp.bytes().await.unwrap().to_vec();
+    let policy = opts.overwrite_policy()?;
+    if policy.check(&file_out_name)? == OutputAction::Skip {
+        println!("[{}] already exists, skipping", file_out_name.display());
+        return Ok(());
+    }
 
-            // decrypt
-            let bytes = cipher.decrypt(&mut bytes).unwrap();
-            let b = copy(&mut &bytes[..], &mut f).await.unwrap();
+    println!("{}: saving file to [{}]", label, file_out_name.display());
 
-            let duration = start.elapsed();
+    // the lesson page is visited once, for the archive check and the playlist
+    let (lesson_info, page) = client.fetch_lesson(lesson).await?;
+    if session.archive.lock().unwrap().is_some() {
+        let lesson_part = page.parts.get(part_idx).ok_or(client::NoSuchIndex {
+            kind: "part",
+            index: part_idx,
+        })?;
+        let id = lesson_info.part_id(lesson_part, hq);
+        let recorded = session
+            .archive
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|archive| archive.contains(&id));
+        if recorded {
             println!(
-                "completed download and decryption of [{}], [{}] bytes, took [{:.2?}]",
-                url, b, duration
+                "[{}] has already been recorded in the archive, skipping",
+                id
             );
+            return Ok(());
+        }
+    }
 
-            f
+    let event_label = label.clone();
+    let events: EventCallback = Arc::new(move |event| {
+        if let Some(observer) = &observer {
+            observer(event);
+        }
+        match event {
+            DownloadEvent::SegmentFinished {
+                idx,
+                bytes,
+                elapsed,
+            } => println!(
+                "{}: completed download and decryption of segment [{}], [{}] bytes, took [{:.2?}]",
+                event_label, idx, bytes, elapsed
+            ),
+            DownloadEvent::SegmentFailed {
+                idx,
+                attempt,
+                error,
+                retrying: true,
+            } => println!(
+                "{}: segment [{}] failed (attempt [{}]), retrying: {}",
+                event_label, idx, attempt, error
+            ),
+            DownloadEvent::SegmentInvalid {
+                idx,
+                attempt,
+                problem,
+            } => println!(
+                "{}: segment [{}] failed validation (attempt [{}]): {}",
+                event_label, idx, attempt, problem
+            ),
+            DownloadEvent::UrlsExpired { idx } => println!(
+                "{}: segment [{}] url expired, refreshing playlist and key",
+                event_label, idx
+            ),
+            DownloadEvent::ConcurrencyChanged { limit } => println!(
+                "{}: now downloading [{}] segments at once",
+                event_label, limit
+            ),
+            _ => {}
         }
     });
 
-    let file_handles = futures::stream::iter(tasks_iter)
-        .buffered(opts.parallel)
-        .collect::<Vec<TokioFile>>()
+    // get playlist for chosen lesson and part, if it doesn't exist, bail
+    let list = client
+        .get_playlist_for(&lesson_info, &page, part_idx, hq, opts.subs)
+        .await?;
+    let part = Arc::new(
+        RefreshablePart::new(client.clone(), lesson, part_idx, hq, list)
+            .with_validation(!opts.no_verify_segments)
+            .with_events(events.clone()),
+    );
+    let segment_count = part.list().files.len();
+    if let Some(title) = &part.list().lesson_title {
+        session
+            .lesson_titles
+            .lock()
+            .unwrap()
+            .insert(lesson, title.clone());
+    }
+
+    println!(
+        "{}: downloading [{}] segments with [{}] threads",
+        label, segment_count, opts.parallel
+    );
+
+    // pick up after the segments an interrupted run already wrote
+    let part_id = part.list().archive_id();
+    let (out_file, completed) = match ResumeState::load(&file_out_name, &part_id, segment_count) {
+        Some(state) => {
+            println!(
+                "{}: resuming after [{}] of [{}] segments",
+                label,
+                state.segment_sizes.len(),
+                segment_count
+            );
+            (
+                PartFile::resume(&file_out_name, state.bytes())?,
+                state.segment_sizes,
+            )
+        }
+        None => (PartFile::create(&file_out_name)?, vec![]),
+    };
+    // an existing state stays valid until it's replaced, the `.part` file only grows past it
+    let resumed = !completed.is_empty();
+
+    let mut downloader = Downloader::new().with_concurrency(opts.parallel);
+    if opts.adaptive {
+        downloader = downloader.with_adaptive_concurrency(1, opts.parallel);
+    }
+    let downloader = downloader
+        .with_retries(opts.retries, Duration::from_secs(1))
+        .with_rate_limiter(session.rate_limiter.clone())
+        .with_pool(session.pool.clone())
+        .with_cancellation(cancellation.clone())
+        .with_abort(session.abort.clone())
+        .with_completed(completed)
+        .on_event(move |event| events(event));
+
+    // download and decrypt, re-resolving the part if its urls expire
+    let result = downloader
+        .download(&*part, BufWriter::new(out_file.file()))
         .await;
+    let summary = match result.map_err(|e| e.downcast::<Cancelled>()) {
+        Ok(summary) => summary,
+        Err(Ok(cancelled)) => {
+            if opts.delete_partial {
+                out_file.discard()?;
+                ResumeState::clear(&file_out_name)?;
+            } else {
+                ResumeState {
+                    id: part_id,
+                    segment_count,
+                    segment_sizes: cancelled.segment_sizes.clone(),
+                }
+                .save(&selene::resume::state_path(&file_out_name))?;
+                println!(
+                    "kept [{}], run the same command again to resume",
+                    out_file.part_path().display()
+                );
+            }
+            return Err(cancelled.into());
+        }
+        Err(Err(e)) => {
+            // without a state to resume from, nothing else would ever pick up the `.part` file
+            if !resumed {
+                if let Err(discard) = out_file.discard() {
+                    eprintln!("{:#}", discard);
+                }
+            }
+            return Err(e);
+        }
+    };
+    let segment_sizes = summary.segment_sizes;
+
+    println!(
+        "{}: all segments merged [{}]",
+        label,
+        out_file.part_path().display()
+    );
+
+    let list = part.list();
+
+    let report =
+        selene::integrity::check_output(out_file.part_path(), &list.durations, &segment_sizes)?;
+    println!("{}: {}", label, report);
+    if !report.passed() && opts.strict_integrity {
+        // resuming would only merge the same broken segments again, start over next time
+        let part_path = out_file.part_path().to_path_buf();
+        out_file.discard()?;
+        ResumeState::clear(&file_out_name)?;
+        anyhow::bail!(
+            "output [{}] failed the integrity check and was discarded",
+            part_path.display()
+        );
+    }
+    if !report.passed() {
+        println!(
+            "{}: keeping output that failed the integrity check, use --strict-integrity to fail instead",
+            label
+        );
+    }
+
+    out_file.persist()?;
+    ResumeState::clear(&file_out_name)?;
+    println!("saved [{}]", file_out_name.display());
+
+    // a part only counts as done once its subtitles are saved too
+    if opts.subs {
+        for (i, track) in list.subtitles.iter().enumerate() {
+            let lang = track.language.clone().unwrap_or_else(|| i.to_string());
+            let ext = if opts.srt { "srt" } else { "vtt" };
+            let sub_name = file_out_name.with_extension(format!("{}.{}", lang, ext));
+            if policy.check(&sub_name)? == OutputAction::Skip {
+                println!("[{}] already exists, skipping", sub_name.display());
+                continue;
+            }
+
+            let vtt = client
+                .download_subtitles(&list, track)
+                .await
+                .context("failed to download subtitles")?;
+            let contents = if opts.srt {
+                selene::subtitles::webvtt_to_srt(&vtt)?
+            } else {
+                vtt
+            };
+            std::fs::write(&sub_name, contents).context("could not write subtitle file")?;
+            println!("saved subtitles [{}]", sub_name.display());
+        }
+
+        if list.subtitles.is_empty() {
+            println!("{}: no subtitles found", label);
+        }
+    }
+
+    if let Some(archive) = session.archive.lock().unwrap().as_mut() {
+        archive.record(&list.archive_id())?;
+    }
 
-    let out_file = File::create(&file_out_name).context("could not create final output file")?;
+    let info_json = file_out_name.with_extension("info.json");
+    if opts.write_info_json || opts.write_nfo {
+        let metadata = selene::metadata::VideoMetadata::from_download(
+            &list,
+            lesson,
+            part_idx,
+            &file_out_name,
+        )?;
 
-    println!("download complete, merging files");
-    for i in file_handles {
-        let mut i = i.into_std().await;
-        i.seek(SeekFrom::Start(0))
-            .context("could not seek tempfile")?;
+        if opts.write_info_json {
+            metadata.write_json(&info_json)?;
+            println!("saved metadata [{}]", info_json.display());
+        }
 
-        let mut r = BufReader::new(i);
-        let _b =
-            std::io::copy(&mut r, &mut &out_file).context("failed to write part to end file")?;
+        if opts.write_nfo {
+            let path = file_out_name.with_extension("nfo");
+            metadata.write_nfo(&path)?;
+            println!("saved metadata [{}]", path.display());
+        }
     }
 
-    println!("all parts merged [{}]", file_out_name);
+    if let Some(command) = &opts.exec {
+        let values = [
+            ("path", file_out_name.display().to_string()),
+            ("title", list.part_title.clone().unwrap_or_default()),
+            ("lesson", lesson.to_string()),
+            ("part", part_idx.to_string()),
+            (
+                "lesson_title",
+                list.lesson_title.clone().unwrap_or_default(),
+            ),
+            ("course", list.course.clone().unwrap_or_default()),
+            (
+                "info_json",
+                if opts.write_info_json {
+                    info_json.display().to_string()
+                } else {
+                    String::new()
+                },
+            ),
+            ("output_dir", opts.output_dir.display().to_string()),
+        ];
+        run_hook(command, &values).await;
+    }
 
     Ok(())
 }
+
+async fn download_attachments(session: &Session, lesson: usize) -> anyhow::Result<()> {
+    let attachments = session
+        .client
+        .get_lesson(lesson)
+        .await
+        .context("failed to get lesson attachments")?
+        .attachments;
+
+    let policy = session.opts.overwrite_policy()?;
+    for attachment in &attachments {
+        let path = attachment_path(&session.opts, lesson, &attachment.name);
+        if policy.check(&path)? == OutputAction::Skip {
+            println!("[{}] already exists, skipping", path.display());
+            continue;
+        }
+
+        let bytes = session.client.download_attachment(attachment).await?;
+        std::fs::create_dir_all(path.parent().unwrap_or(&session.opts.output_dir))
+            .context("could not create attachment directory")?;
+        std::fs::write(&path, bytes).context("could not write attachment file")?;
+        println!("saved attachment [{}]", path.display());
+    }
+
+    if attachments.is_empty() {
+        println!("no attachments found for lesson [{}]", lesson);
+    }
+    Ok(())
+}
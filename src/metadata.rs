@@ -0,0 +1,185 @@
+use anyhow::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+use crate::schoolism::client::SchoolismVideoList;
+
+// sidecar written next to every downloaded video, used to index the archive
+#[derive(Serialize)]
+pub struct VideoMetadata {
+    pub course: Option<String>,
+    pub lesson: LessonMetadata,
+    pub part: PartMetadata,
+    pub source: SourceMetadata,
+    pub output: OutputMetadata,
+}
+
+#[derive(Serialize)]
+pub struct LessonMetadata {
+    pub index: usize,
+    pub title: Option<String>,
+    pub url: String,
+}
+
+#[derive(Serialize)]
+pub struct PartMetadata {
+    pub index: usize,
+    pub title: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SourceMetadata {
+    pub playlist_url: String,
+    pub variant_url: String,
+    pub variant_attributes: HashMap<String, String>,
+    pub segment_count: usize,
+    pub duration: f64, // seconds, sum of `EXTINF`
+}
+
+#[derive(Serialize)]
+pub struct OutputMetadata {
+    pub file_name: String,
+    pub size: u64,
+    pub sha256: String,
+    pub downloaded_at: String, // rfc 3339
+}
+
+impl VideoMetadata {
+    // hashes the finished output, so this should only be called once it's fully written
+    pub fn from_download(
+        list: &SchoolismVideoList,
+        lesson_idx: usize,
+        part_idx: usize,
+        output: &Path,
+    ) -> anyhow::Result<Self> {
+        let (size, sha256) = hash_file(output)?;
+        let file_name = output
+            .file_name()
+            .map(|it| it.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        Ok(Self {
+            course: list.course.clone(),
+            lesson: LessonMetadata {
+                index: lesson_idx,
+                title: list.lesson_title.clone(),
+                url: list.referer(),
+            },
+            part: PartMetadata {
+                index: part_idx,
+                title: list.part_title.clone(),
+            },
+            source: SourceMetadata {
+                playlist_url: list.playlist_url.clone(),
+                variant_url: list.variant_url.clone(),
+                variant_attributes: list.variant_attribs.clone(),
+                segment_count: list.files.len(),
+                duration: list.total_duration(),
+            },
+            output: OutputMetadata {
+                file_name,
+                size,
+                sha256,
+                downloaded_at: chrono::Utc::now().to_rfc3339(),
+            },
+        })
+    }
+
+    pub fn write_json(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(self).context("could not serialize metadata")?;
+        std::fs::write(path, json).context("could not write metadata json")
+    }
+
+    // kodi style episode nfo, lessons map to seasons and parts to episodes
+    pub fn write_nfo(&self, path: &Path) -> anyhow::Result<()> {
+        let tag = |name: &str, value: &str| format!("  <{0}>{1}</{0}>\n", name, xml_escape(value));
+
+        let mut nfo = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<episodedetails>\n",
+        );
+        let title = self
+            .part
+            .title
+            .clone()
+            .unwrap_or_else(|| format!("Part {}", self.part.index));
+        nfo.push_str(&tag("title", &title));
+        if let Some(course) = &self.course {
+            nfo.push_str(&tag("showtitle", course));
+        }
+        nfo.push_str(&tag("season", &self.lesson.index.to_string()));
+        nfo.push_str(&tag("episode", &self.part.index.to_string()));
+        nfo.push_str(&tag(
+            "runtime",
+            &((self.source.duration / 60.0).round() as u64).to_string(),
+        ));
+        if let Some(lesson) = &self.lesson.title {
+            nfo.push_str(&tag("plot", lesson));
+        }
+        nfo.push_str(&tag("dateadded", &self.output.downloaded_at));
+        nfo.push_str("</episodedetails>\n");
+
+        std::fs::write(path, nfo).context("could not write metadata nfo")
+    }
+}
+
+// (size in bytes, lowercase hex sha-256)
+pub fn hash_file(path: &Path) -> anyhow::Result<(u64, String)> {
+    let mut file = std::fs::File::open(path).context("could not open file for hashing")?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+
+    loop {
+        let read = file
+            .read(&mut buf)
+            .context("could not read file for hashing")?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        size += read as u64;
+    }
+
+    let hex = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Ok((size, hex))
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod metadata_tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn hashes_file_contents() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        f.write_all(b"abc").unwrap();
+
+        let (size, hash) = hash_file(f.path()).unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(
+            hash,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn escapes_xml() {
+        assert_eq!(xml_escape("<a & 'b'>"), "&lt;a &amp; &apos;b&apos;&gt;");
+    }
+}
@@ -1,31 +1,48 @@
 use anyhow::Context;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::str::FromStr;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SubPlaylist {
     pub url: String,
     pub attribs: HashMap<String, String>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TrackInfo {
     info: String,
     pub name: String,
 }
 
-#[derive(Clone)]
+impl TrackInfo {
+    // `#EXTINF:<duration>,[<title>]`
+    pub fn duration(&self) -> Option<f64> {
+        self.info.split(',').next()?.trim().parse().ok()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct KeyInfo {
     pub method: String,
     pub uri: String, 
     pub iv: String,
 }
 
-#[derive(Clone)]
+// alternative renditions, e.g. `#EXT-X-MEDIA:TYPE=SUBTITLES,...`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MediaInfo {
+    pub media_type: String,
+    pub uri: Option<String>,
+    pub attribs: HashMap<String, String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct M3U {
     pub directives: HashMap<String, String>,
     pub tracklist: Vec<TrackInfo>,
     pub subplaylists: Vec<SubPlaylist>,
+    pub media: Vec<MediaInfo>,
     pub key_info: Option<KeyInfo>,
 }
 
@@ -40,6 +57,12 @@ impl M3U {
         }
         unreachable!("invalid m3u file provided")
     }
+
+    pub fn subtitle_media(&self) -> impl Iterator<Item = &MediaInfo> {
+        self.media
+            .iter()
+            .filter(|it| it.media_type.eq_ignore_ascii_case("SUBTITLES"))
+    }
 }
 
 impl FromStr for M3U {
@@ -56,8 +79,9 @@ impl FromStr for M3U {
         let mut tracklist = vec![];
         let mut directives = HashMap::new();
         let mut sub_indices = vec![];
+        let mut media = vec![];
         let mut key_info = None;
-        
+
 
         while let Some(line) = lines.next() {
             // secondary sources / subindices
@@ -89,6 +113,17 @@ impl FromStr for M3U {
                     attribs,
                 });
             }
+            // alternative renditions (subtitles, audio)
+            else if line.starts_with("#EXT-X-MEDIA:") {
+                let (_, value) = read_directive(line)?;
+                let mut attribs = read_attribute_list(value);
+
+                media.push(MediaInfo {
+                    media_type: attribs.remove("TYPE").unwrap_or_default(),
+                    uri: attribs.remove("URI"),
+                    attribs,
+                });
+            }
             // key
             else if line.starts_with("#EXT-X-KEY:") {
                 let (_, value) = read_directive(line)?;
@@ -143,7 +178,7 @@ impl FromStr for M3U {
             }
             // unknown line
             else {
-                println!("unknown line detected: [{}]", line);
+                eprintln!("unknown line detected: [{}]", line);
             }
         }
 
@@ -151,11 +186,45 @@ impl FromStr for M3U {
             directives,
             tracklist,
             subplaylists: sub_indices,
+            media,
             key_info,
         })
     }
 }
 
+// attribute lists where quoted values may contain commas, quotes are stripped
+// `TYPE=SUBTITLES,NAME="English, CC",URI="subs/en.m3u8"`
+fn read_attribute_list(value: &str) -> HashMap<String, String> {
+    let mut attribs = HashMap::new();
+    let mut rest = value.trim();
+
+    while !rest.is_empty() {
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => break,
+        };
+        let key = rest[..eq].trim().to_string();
+        rest = &rest[eq + 1..];
+
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            let value = &quoted[..end];
+            rest = quoted.get(end + 1..).unwrap_or("");
+            value
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = &rest[..end];
+            rest = &rest[end..];
+            value
+        };
+
+        attribs.insert(key, value.to_string());
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+    }
+
+    attribs
+}
+
 fn read_directive(line: &str) -> anyhow::Result<(&str, &str)> {
     let mut l = line.splitn(2, ":");
     Ok((
@@ -165,3 +234,68 @@ fn read_directive(line: &str) -> anyhow::Result<(&str, &str)> {
             .context(format!("directive value not found: [{}]", line))?,
     ))
 }
+
+#[cfg(test)]
+mod m3u_tests {
+    use super::*;
+
+    #[test]
+    fn attribute_list_handles_quoted_commas() {
+        let attribs =
+            read_attribute_list(r#"TYPE=SUBTITLES,NAME="English, CC",DEFAULT=NO,URI="en.m3u8""#);
+        assert_eq!(attribs["TYPE"], "SUBTITLES");
+        assert_eq!(attribs["NAME"], "English, CC");
+        assert_eq!(attribs["DEFAULT"], "NO");
+        assert_eq!(attribs["URI"], "en.m3u8");
+    }
+
+    #[test]
+    fn parses_subtitle_media_and_segment_playlists() {
+        let primary: M3U = "#EXTM3U\n\
+            #EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"English\",LANGUAGE=\"en\",URI=\"subs/en.m3u8\"\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=800000,SUBTITLES=\"subs\"\n\
+            https://example.com/low/index.m3u8\n"
+            .parse()
+            .unwrap();
+
+        let subs: Vec<_> = primary.subtitle_media().collect();
+        assert_eq!(subs.len(), 1);
+        assert_eq!(subs[0].uri.as_deref(), Some("subs/en.m3u8"));
+        assert_eq!(subs[0].attribs["LANGUAGE"], "en");
+        assert!(primary.is_primary());
+
+        let segments: M3U = "#EXTM3U\n\
+            #EXT-X-TARGETDURATION:10\n\
+            #EXTINF:10.0,\n\
+            en_0.vtt\n\
+            #EXTINF:4.5,\n\
+            en_1.vtt\n\
+            #EXT-X-ENDLIST\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(segments.tracklist.len(), 2);
+        assert_eq!(segments.tracklist[1].name, "en_1.vtt");
+        assert_eq!(segments.tracklist[1].duration(), Some(4.5));
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let playlist: M3U = "#EXTM3U\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"https://example.com/key\",IV=0x0102\n\
+            #EXTINF:6.0,\n\
+            seg_0.ts\n\
+            #EXT-X-ENDLIST\n"
+            .parse()
+            .unwrap();
+
+        let json = serde_json::to_value(&playlist).unwrap();
+        assert_eq!(json["key_info"]["method"], "AES-128");
+        assert_eq!(json["tracklist"][0]["name"], "seg_0.ts");
+
+        let parsed: M3U = serde_json::from_value(json).unwrap();
+        let key = parsed.key_info.unwrap();
+        assert_eq!(key.iv, "0x0102");
+        assert_eq!(parsed.tracklist[0].duration(), Some(6.0));
+    }
+}
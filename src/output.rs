@@ -0,0 +1,288 @@
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// what to do when the final output file already exists
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OverwritePolicy {
+    Overwrite,
+    NoOverwrite, // fail before downloading anything
+    SkipExisting,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum OutputAction {
+    Write,
+    Skip,
+}
+
+impl OverwritePolicy {
+    pub fn check(self, path: &Path) -> anyhow::Result<OutputAction> {
+        if !path.exists() {
+            return Ok(OutputAction::Write);
+        }
+
+        match self {
+            OverwritePolicy::Overwrite => Ok(OutputAction::Write),
+            OverwritePolicy::SkipExisting => Ok(OutputAction::Skip),
+            OverwritePolicy::NoOverwrite => {
+                anyhow::bail!("output file already exists [{}]", path.display())
+            }
+        }
+    }
+}
+
+// output written to `<name>.part` next to its destination and renamed into place once
+// complete, so an interrupted download never leaves a truncated file behind the real name
+pub struct PartFile {
+    path: PathBuf,
+    part_path: PathBuf,
+    file: File,
+}
+
+impl PartFile {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let part_path = part_path(path);
+        let file = File::create(&part_path).context(format!(
+            "could not create output file [{}]",
+            part_path.display()
+        ))?;
+
+        Ok(Self {
+            path: path.into(),
+            part_path,
+            file,
+        })
+    }
+
+    // reopens an interrupted download, dropping anything past the first `len` bytes
+    pub fn resume(path: &Path, len: u64) -> anyhow::Result<Self> {
+        let part_path = part_path(path);
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&part_path)
+            .context(format!(
+                "could not open output file [{}]",
+                part_path.display()
+            ))?;
+        file.set_len(len)
+            .context(format!("could not truncate [{}]", part_path.display()))?;
+        file.seek(SeekFrom::End(0))
+            .context(format!("could not seek [{}]", part_path.display()))?;
+
+        Ok(Self {
+            path: path.into(),
+            part_path,
+            file,
+        })
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn part_path(&self) -> &Path {
+        &self.part_path
+    }
+
+    // flushes to disk before the rename, so the final name only ever points at complete data
+    pub fn persist(self) -> anyhow::Result<PathBuf> {
+        self.file
+            .sync_all()
+            .context(format!("could not flush [{}]", self.part_path.display()))?;
+        drop(self.file);
+
+        std::fs::rename(&self.part_path, &self.path).context(format!(
+            "could not rename [{}] to [{}]",
+            self.part_path.display(),
+            self.path.display()
+        ))?;
+        Ok(self.path)
+    }
+
+    pub fn discard(self) -> anyhow::Result<()> {
+        drop(self.file);
+        std::fs::remove_file(&self.part_path)
+            .context(format!("could not remove [{}]", self.part_path.display()))
+    }
+}
+
+// writes segments to the output in playlist order as they complete, holding on to the ones
+// that finish early until everything before them is written
+// callers bound how far ahead of the write position downloads may run, that also bounds
+// how much sits in memory here
+pub struct OrderedWriter<W: Write> {
+    inner: W,
+    next: usize,
+    pending: BTreeMap<usize, Vec<u8>>,
+    sizes: Vec<u64>,
+}
+
+impl<W: Write> OrderedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self::resume(inner, vec![])
+    }
+
+    // continues after segments that are already in `inner`, given their sizes
+    pub fn resume(inner: W, sizes: Vec<u64>) -> Self {
+        Self {
+            inner,
+            next: sizes.len(),
+            pending: BTreeMap::new(),
+            sizes,
+        }
+    }
+
+    // returns the number of segments written, including this one, if any were
+    pub fn push(&mut self, idx: usize, data: Vec<u8>) -> anyhow::Result<usize> {
+        if idx < self.next || self.pending.contains_key(&idx) {
+            anyhow::bail!("segment [{}] was written twice", idx);
+        }
+        self.pending.insert(idx, data);
+
+        let mut written = 0;
+        while let Some(data) = self.pending.remove(&self.next) {
+            self.inner
+                .write_all(&data)
+                .context(format!("failed to write segment [{}] to output", self.next))?;
+            self.sizes.push(data.len() as u64);
+            self.next += 1;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    // index of the next segment to be written
+    pub fn position(&self) -> usize {
+        self.next
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    // byte lengths of the segments written so far, in order
+    pub fn segment_sizes(&self) -> &[u64] {
+        &self.sizes
+    }
+
+    // fails if segments are still waiting on an earlier one that never arrived
+    pub fn finish(self) -> anyhow::Result<(W, Vec<u64>)> {
+        if !self.pending.is_empty() {
+            anyhow::bail!(
+                "segment [{}] is missing, [{}] later segments were never written",
+                self.next,
+                self.pending.len()
+            );
+        }
+        self.finish_partial()
+    }
+
+    // flushes what has been written so far, dropping segments still waiting on earlier ones
+    pub fn finish_partial(mut self) -> anyhow::Result<(W, Vec<u64>)> {
+        self.inner.flush().context("failed to flush output")?;
+        Ok((self.inner, self.sizes))
+    }
+}
+
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut name: OsString = path.as_os_str().into();
+    name.push(".part");
+    name.into()
+}
+
+#[cfg(test)]
+mod output_tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn renames_part_file_on_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.mp4");
+
+        let part = PartFile::create(&path).unwrap();
+        assert_eq!(part.part_path(), dir.path().join("video.mp4.part"));
+        part.file().write_all(b"data").unwrap();
+        assert!(!path.exists());
+
+        part.persist().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        assert!(!part_path(&path).exists());
+    }
+
+    #[test]
+    fn resumed_part_file_appends_after_len() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.mp4");
+        std::fs::write(part_path(&path), b"abcpartial").unwrap();
+
+        let part = PartFile::resume(&path, 3).unwrap();
+        part.file().write_all(b"d").unwrap();
+        part.persist().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcd");
+    }
+
+    #[test]
+    fn writes_segments_in_order() {
+        let mut writer = OrderedWriter::new(vec![]);
+
+        assert_eq!(writer.push(2, b"c".to_vec()).unwrap(), 0);
+        assert_eq!(writer.push(1, b"bb".to_vec()).unwrap(), 0);
+        assert_eq!(writer.pending(), 2);
+        assert_eq!(writer.push(0, b"a".to_vec()).unwrap(), 3);
+        assert_eq!(writer.position(), 3);
+        assert!(writer.push(1, b"bb".to_vec()).is_err());
+
+        let (out, sizes) = writer.finish().unwrap();
+        assert_eq!(out, b"abbc");
+        assert_eq!(sizes, vec![1, 2, 1]);
+    }
+
+    #[test]
+    fn resumes_after_written_segments() {
+        let mut writer = OrderedWriter::resume(b"ab".to_vec(), vec![1, 1]);
+        writer.push(3, b"d".to_vec()).unwrap();
+        writer.push(2, b"c".to_vec()).unwrap();
+        writer.push(5, b"f".to_vec()).unwrap();
+
+        let (out, sizes) = writer.finish_partial().unwrap();
+        assert_eq!(out, b"abcd");
+        assert_eq!(sizes, vec![1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn finish_fails_on_gaps() {
+        let mut writer = OrderedWriter::new(vec![]);
+        writer.push(1, b"b".to_vec()).unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn applies_overwrite_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.mp4");
+
+        for policy in &[
+            OverwritePolicy::Overwrite,
+            OverwritePolicy::NoOverwrite,
+            OverwritePolicy::SkipExisting,
+        ] {
+            assert_eq!(policy.check(&path).unwrap(), OutputAction::Write);
+        }
+
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(
+            OverwritePolicy::Overwrite.check(&path).unwrap(),
+            OutputAction::Write
+        );
+        assert_eq!(
+            OverwritePolicy::SkipExisting.check(&path).unwrap(),
+            OutputAction::Skip
+        );
+        assert!(OverwritePolicy::NoOverwrite.check(&path).is_err());
+    }
+}
@@ -0,0 +1,364 @@
+use anyhow::Context;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+// persistent list of lesson parts to download, kept as a json file so several people can
+// drop requests into it and a worker can process them later
+// every change holds an exclusive lock on `<file>.lock` while it reloads the file and writes
+// it back through a rename, so jobs added while a worker is running aren't lost
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+impl std::fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub id: u64,
+    pub lesson: usize,
+    pub part: usize,
+    pub hq: bool,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub added_at: String,   // rfc 3339
+    pub updated_at: String, // rfc 3339
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct QueueFile {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+pub struct JobQueue {
+    path: PathBuf,
+}
+
+impl JobQueue {
+    // the file is created on the first change
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn jobs(&self) -> anyhow::Result<Vec<Job>> {
+        Ok(self.load()?.jobs)
+    }
+
+    // returns the id of the new job, or of an unfinished job for the same part
+    pub fn add(&self, lesson: usize, part: usize, hq: bool) -> anyhow::Result<u64> {
+        self.update(|queue| {
+            let existing = queue.jobs.iter().find(|job| {
+                job.lesson == lesson
+                    && job.part == part
+                    && job.hq == hq
+                    && job.status != JobStatus::Done
+            });
+            if let Some(job) = existing {
+                return job.id;
+            }
+
+            queue.next_id += 1;
+            let now = now();
+            queue.jobs.push(Job {
+                id: queue.next_id,
+                lesson,
+                part,
+                hq,
+                status: JobStatus::Pending,
+                attempts: 0,
+                last_error: None,
+                added_at: now.clone(),
+                updated_at: now,
+            });
+            queue.next_id
+        })
+    }
+
+    pub fn remove(&self, id: u64) -> anyhow::Result<bool> {
+        self.update(|queue| {
+            let before = queue.jobs.len();
+            queue.jobs.retain(|job| job.id != id);
+            queue.jobs.len() != before
+        })
+    }
+
+    // puts failed jobs, or the given job, back in line with a fresh attempt count
+    pub fn retry(&self, id: Option<u64>) -> anyhow::Result<usize> {
+        self.update(|queue| {
+            let mut count = 0;
+            for job in &mut queue.jobs {
+                let selected = match id {
+                    Some(id) => job.id == id && job.status != JobStatus::Running,
+                    None => job.status == JobStatus::Failed,
+                };
+                if selected {
+                    job.status = JobStatus::Pending;
+                    job.attempts = 0;
+                    job.updated_at = now();
+                    count += 1;
+                }
+            }
+            count
+        })
+    }
+
+    // only one worker processes the queue at a time, the lock is held until the returned
+    // guard is dropped and released by the os if the worker dies
+    pub fn lock_worker(&self) -> anyhow::Result<WorkerLock> {
+        let path = sibling(&self.path, ".worker");
+        let file = open_lock(&path)?;
+        file.try_lock_exclusive().context(format!(
+            "another worker is already processing [{}]",
+            self.path.display()
+        ))?;
+        Ok(WorkerLock { _file: file })
+    }
+
+    // jobs left running by a worker that didn't exit cleanly go back to pending, holding the
+    // worker lock means no other worker can still be running them
+    pub fn reset_running(&self, _worker: &WorkerLock) -> anyhow::Result<usize> {
+        self.update(|queue| {
+            let mut count = 0;
+            for job in &mut queue.jobs {
+                if job.status == JobStatus::Running {
+                    job.status = JobStatus::Pending;
+                    job.updated_at = now();
+                    count += 1;
+                }
+            }
+            count
+        })
+    }
+
+    // marks the oldest pending job as running and counts the attempt
+    pub fn claim_next(&self) -> anyhow::Result<Option<Job>> {
+        self.update(|queue| {
+            let job = queue
+                .jobs
+                .iter_mut()
+                .find(|job| job.status == JobStatus::Pending)?;
+            job.status = JobStatus::Running;
+            job.attempts += 1;
+            job.updated_at = now();
+            Some(job.clone())
+        })
+    }
+
+    // failed jobs are retried until they've used up `max_attempts`
+    pub fn finish(
+        &self,
+        id: u64,
+        result: Result<(), String>,
+        max_attempts: u32,
+    ) -> anyhow::Result<JobStatus> {
+        self.update(|queue| {
+            let job = match queue.jobs.iter_mut().find(|job| job.id == id) {
+                Some(job) => job,
+                // removed while it was running
+                None => return JobStatus::Done,
+            };
+
+            job.status = match &result {
+                Ok(()) => JobStatus::Done,
+                Err(_) if job.attempts < max_attempts => JobStatus::Pending,
+                Err(_) => JobStatus::Failed,
+            };
+            job.last_error = result.err();
+            job.updated_at = now();
+            job.status
+        })
+    }
+
+    // gives the job back without counting the attempt, e.g. when interrupted
+    pub fn release(&self, id: u64) -> anyhow::Result<()> {
+        self.update(|queue| {
+            if let Some(job) = queue.jobs.iter_mut().find(|job| job.id == id) {
+                job.status = JobStatus::Pending;
+                job.attempts = job.attempts.saturating_sub(1);
+                job.updated_at = now();
+            }
+        })
+    }
+
+    fn load(&self) -> anyhow::Result<QueueFile> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(json) => serde_json::from_str(&json)
+                .context(format!("invalid queue file [{}]", self.path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(QueueFile::default()),
+            Err(e) => Err(e).context(format!(
+                "could not read queue file [{}]",
+                self.path.display()
+            )),
+        }
+    }
+
+    fn update<T>(&self, change: impl FnOnce(&mut QueueFile) -> T) -> anyhow::Result<T> {
+        let lock_path = sibling(&self.path, ".lock");
+        let lock = open_lock(&lock_path)?;
+        lock.lock_exclusive().context(format!(
+            "could not lock queue file [{}]",
+            lock_path.display()
+        ))?;
+
+        let mut queue = self.load()?;
+        let result = change(&mut queue);
+
+        // readers don't take the lock, they only ever see the old or the new file
+        let json = serde_json::to_string_pretty(&queue).context("could not serialize queue")?;
+        let tmp = sibling(&self.path, &format!(".{}.part", std::process::id()));
+        std::fs::write(&tmp, json)
+            .context(format!("could not write queue file [{}]", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path).context(format!(
+            "could not replace queue file [{}]",
+            self.path.display()
+        ))?;
+        Ok(result)
+    }
+}
+
+// held by the process working through the queue, see `JobQueue::lock_worker`
+pub struct WorkerLock {
+    _file: File,
+}
+
+fn open_lock(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .context(format!("could not open lock file [{}]", path.display()))
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.as_os_str().into();
+    name.push(suffix);
+    name.into()
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339()
+}
+
+#[cfg(test)]
+mod queue_tests {
+    use super::*;
+
+    fn queue() -> (tempfile::TempDir, JobQueue) {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::new(&dir.path().join("queue.json"));
+        (dir, queue)
+    }
+
+    #[test]
+    fn adds_and_removes_jobs() {
+        let (_dir, queue) = queue();
+        assert!(queue.jobs().unwrap().is_empty());
+
+        let a = queue.add(1, 2, true).unwrap();
+        let b = queue.add(1, 3, false).unwrap();
+        assert_ne!(a, b);
+        // unfinished duplicates aren't queued twice
+        assert_eq!(queue.add(1, 2, true).unwrap(), a);
+
+        assert!(queue.remove(a).unwrap());
+        assert!(!queue.remove(a).unwrap());
+        let jobs = queue.jobs().unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].id, b);
+    }
+
+    #[test]
+    fn retries_until_attempts_run_out() {
+        let (_dir, queue) = queue();
+        let id = queue.add(4, 1, false).unwrap();
+
+        let job = queue.claim_next().unwrap().unwrap();
+        assert_eq!(
+            (job.id, job.status, job.attempts),
+            (id, JobStatus::Running, 1)
+        );
+        assert_eq!(
+            queue.finish(id, Err("timeout".into()), 2).unwrap(),
+            JobStatus::Pending
+        );
+
+        queue.claim_next().unwrap().unwrap();
+        assert_eq!(
+            queue.finish(id, Err("timeout".into()), 2).unwrap(),
+            JobStatus::Failed
+        );
+        assert!(queue.claim_next().unwrap().is_none());
+
+        assert_eq!(queue.retry(None).unwrap(), 1);
+        let job = queue.claim_next().unwrap().unwrap();
+        assert_eq!(job.attempts, 1);
+        assert_eq!(job.last_error.as_deref(), Some("timeout"));
+        assert_eq!(queue.finish(id, Ok(()), 2).unwrap(), JobStatus::Done);
+    }
+
+    #[test]
+    fn released_and_stale_jobs_return_to_pending() {
+        let (_dir, queue) = queue();
+        let id = queue.add(1, 1, false).unwrap();
+
+        queue.claim_next().unwrap();
+        queue.release(id).unwrap();
+        let job = queue.claim_next().unwrap().unwrap();
+        assert_eq!(job.attempts, 1);
+
+        let worker = queue.lock_worker().unwrap();
+        assert_eq!(queue.reset_running(&worker).unwrap(), 1);
+        assert_eq!(queue.jobs().unwrap()[0].status, JobStatus::Pending);
+    }
+
+    #[test]
+    fn only_one_worker_at_a_time() {
+        let (_dir, queue) = queue();
+        let worker = queue.lock_worker().unwrap();
+        assert!(queue.lock_worker().is_err());
+        // jobs can still be added while the worker holds its lock
+        queue.add(1, 1, false).unwrap();
+
+        drop(worker);
+        queue.lock_worker().unwrap();
+    }
+
+    #[test]
+    fn concurrent_adds_are_all_kept() {
+        let (_dir, queue) = queue();
+        let queue = std::sync::Arc::new(queue);
+
+        let adders: Vec<_> = (0..8)
+            .map(|part| {
+                let queue = queue.clone();
+                std::thread::spawn(move || queue.add(1, part, false).unwrap())
+            })
+            .collect();
+        for adder in adders {
+            adder.join().unwrap();
+        }
+
+        assert_eq!(queue.jobs().unwrap().len(), 8);
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::Context;
+use std::sync::Mutex;
+use tokio::time::{delay_for, Duration, Instant};
+
+// token bucket shared between every task that should count towards the same limit
+// tokens are bytes for bandwidth limits, or requests for request rate limits
+// consumers may overdraw the bucket, they then wait until it's back in credit, so a
+// single large chunk never stalls forever on a small bucket
+pub struct RateLimiter {
+    rate: f64, // tokens per second
+    capacity: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    // the bucket starts full and holds at most one second's worth of tokens
+    pub fn new(rate: f64) -> Self {
+        Self::with_capacity(rate, rate.max(1.0))
+    }
+
+    // panics unless `rate` is a positive number, a zero rate would wait forever
+    pub fn with_capacity(rate: f64, capacity: f64) -> Self {
+        assert!(
+            rate > 0.0 && rate.is_finite(),
+            "rate must be a positive number, got [{}]",
+            rate
+        );
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new(BucketState {
+                tokens: capacity,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub async fn acquire(&self, tokens: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * self.rate).min(self.capacity);
+            state.last_refill = now;
+
+            state.tokens -= tokens as f64;
+            if state.tokens >= 0.0 {
+                return;
+            }
+            -state.tokens / self.rate
+        };
+
+        delay_for(Duration::from_secs_f64(wait)).await;
+    }
+}
+
+// `500K`, `2M`, `1.5G` or plain bytes, binary multiples like youtube-dl
+pub fn parse_rate(value: &str) -> anyhow::Result<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .context(format!("invalid rate: [{}]", value))?;
+    let multiplier = match suffix.trim().to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1u64,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => anyhow::bail!("invalid rate suffix: [{}]", value),
+    };
+
+    let rate = (number * multiplier as f64) as u64;
+    if rate == 0 {
+        anyhow::bail!("rate must be greater than zero: [{}]", value);
+    }
+    Ok(rate)
+}
+
+#[cfg(test)]
+mod ratelimit_tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("1024").unwrap(), 1024);
+        assert_eq!(parse_rate("500K").unwrap(), 500 * 1024);
+        assert_eq!(parse_rate("2M").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_rate("1.5m").unwrap(), 1536 * 1024);
+        assert_eq!(parse_rate("1GB").unwrap(), 1 << 30);
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("2X").is_err());
+        assert!(parse_rate("0").is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "rate must be a positive number")]
+    fn rejects_zero_rate() {
+        RateLimiter::new(0.0);
+    }
+
+    #[tokio::test]
+    async fn waits_once_bucket_is_empty() {
+        let limiter = RateLimiter::with_capacity(1000.0, 100.0);
+
+        let start = Instant::now();
+        limiter.acquire(100).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // 100 tokens in debt at 1000/s is a 100ms wait
+        limiter.acquire(100).await;
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crate::output;
+
+// written next to the `.part` file when a download is interrupted, records which segments
+// made it to disk so running the same download again picks up after them
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ResumeState {
+    pub id: String, // archive id of the part, see `archive::part_id`
+    pub segment_count: usize,
+    pub segment_sizes: Vec<u64>, // segments fully written to the `.part` file, in order
+}
+
+impl ResumeState {
+    pub fn bytes(&self) -> u64 {
+        self.segment_sizes.iter().sum()
+    }
+
+    // replaces the previous state in one step, so there's always one to resume from
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string(self).context("could not serialize resume state")?;
+        let tmp = output::part_path(path);
+        std::fs::write(&tmp, json)
+            .context(format!("could not write resume state [{}]", tmp.display()))?;
+        std::fs::rename(&tmp, path).context(format!(
+            "could not replace resume state [{}]",
+            path.display()
+        ))
+    }
+
+    // only returns state that matches this download and whose `.part` file is still intact
+    pub fn load(output: &Path, id: &str, segment_count: usize) -> Option<Self> {
+        let json = std::fs::read_to_string(state_path(output)).ok()?;
+        let state: Self = serde_json::from_str(&json).ok()?;
+        let part_len = std::fs::metadata(output::part_path(output)).ok()?.len();
+
+        let matches = state.id == id
+            && state.segment_count == segment_count
+            && state.segment_sizes.len() <= segment_count
+            && part_len >= state.bytes();
+        if matches {
+            Some(state)
+        } else {
+            None
+        }
+    }
+
+    // removes the state file, a missing one is fine
+    pub fn clear(output: &Path) -> anyhow::Result<()> {
+        let path = state_path(output);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).context(format!(
+                "could not remove resume state [{}]",
+                path.display()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn state_path(output: &Path) -> PathBuf {
+    let mut name: OsString = output.as_os_str().into();
+    name.push(".resume.json");
+    name.into()
+}
+
+#[cfg(test)]
+mod resume_tests {
+    use super::*;
+
+    #[test]
+    fn loads_only_matching_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("video.mp4");
+        let state = ResumeState {
+            id: "lesson:/part".into(),
+            segment_count: 4,
+            segment_sizes: vec![3, 2],
+        };
+        state.save(&state_path(&output)).unwrap();
+
+        // no part file yet
+        assert_eq!(ResumeState::load(&output, "lesson:/part", 4), None);
+
+        std::fs::write(output::part_path(&output), b"abcde").unwrap();
+        assert_eq!(ResumeState::load(&output, "lesson:/part", 4), Some(state));
+        assert_eq!(ResumeState::load(&output, "lesson:/other", 4), None);
+        assert_eq!(ResumeState::load(&output, "lesson:/part", 5), None);
+
+        std::fs::write(output::part_path(&output), b"abc").unwrap();
+        assert_eq!(ResumeState::load(&output, "lesson:/part", 4), None);
+
+        ResumeState::clear(&output).unwrap();
+        ResumeState::clear(&output).unwrap();
+        assert!(!state_path(&output).exists());
+    }
+}
@@ -1,67 +1,87 @@
-use crate::mp3url::M3U;
+use super::pacing::Pacing;
+use crate::http::HttpConfig;
+use crate::mp3url::{SubPlaylist, M3U};
+use crate::ratelimit::RateLimiter;
 use anyhow::Context;
 use anyhow::Result;
 use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
-use tokio::time::{delay_for, Duration};
 
 // the www is important
 pub const SCHOOLISM_URL: &'static str = "https://www.schoolism.com";
 static LOGIN_FAILED_RE: Lazy<regex::Regex> =
     Lazy::new(|| regex::Regex::new(r"login\.colorBox\.php\?loginError=true").unwrap());
 
-// chrome user agent
-const USER_AGENT: &'static str = "Mozilla/5.0 (Windows NT 6.1; WOW64) \
-    AppleWebKit/537.36 (KHTML, like Gecko) \
-    Chrome/86.0.4230.1 \
-    Safari/537.36";
-
-// firefox user agent
-// const USER_AGENT: &'static str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:82.0) \
-//     Gecko/20100101 Firefox/82.0";
-
 pub struct ClientInit {
     net_client: Arc<reqwest::Client>,
+    request_limiter: Option<Arc<RateLimiter>>,
+    pacing: Pacing,
     username: String,
     password: String,
 }
 
 pub struct ClientConnected {
     net_client: Arc<reqwest::Client>,
+    request_limiter: Option<Arc<RateLimiter>>,
+    pacing: Pacing,
 }
 
 impl ClientInit {
     pub fn new(username: &str, password: &str) -> anyhow::Result<Self> {
+        Self::with_config(username, password, &HttpConfig::default())
+    }
+
+    pub fn with_config(
+        username: &str,
+        password: &str,
+        config: &HttpConfig,
+    ) -> anyhow::Result<Self> {
         let username = username.into();
         let password = password.into();
-        let net_client = reqwest::ClientBuilder::new()
+        let net_client = config
+            .client_builder()?
             .cookie_store(true)
-            .user_agent(USER_AGENT)
             .build()
             .context("could not create new http client")?
             .into();
 
         Ok(Self {
             net_client,
+            request_limiter: None,
+            pacing: Pacing::default(),
             username,
             password,
         })
     }
 
+    // limits page, playlist and key requests, segment downloads are not affected
+    pub fn with_request_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.request_limiter = Some(limiter);
+        self
+    }
+
+    pub fn with_pacing(mut self, pacing: Pacing) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
     pub async fn connect(self) -> anyhow::Result<ClientConnected> {
         let net_client = self.net_client;
+        let request_limiter = self.request_limiter;
         let form = reqwest::multipart::Form::new()
             .text("email", self.username)
             .text("password", self.password)
             .text("submit", "Login");
 
-        let resp = net_client
-            .post(SCHOOLISM_URL)
-            .multipart(form)
-            .send()
-            .await
-            .context("could not submit login form")?;
+        let resp = send_limited(
+            &request_limiter,
+            net_client.post(SCHOOLISM_URL).multipart(form),
+        )
+        .await
+        .context("could not submit login form")?;
 
         let page = resp.text().await.context("could not get page text")?;
 
@@ -69,61 +89,90 @@ impl ClientInit {
             anyhow::bail!("login failed")
         }
 
-        Ok(ClientConnected { net_client })
+        Ok(ClientConnected {
+            net_client,
+            request_limiter,
+            pacing: self.pacing,
+        })
     }
 }
 
 #[derive(Debug)]
 pub struct Key(Vec<u8>);
 impl ClientConnected {
+    async fn send(&self, request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+        send_limited(&self.request_limiter, request).await
+    }
+
+    // page navigation, paced to look like a person clicking through the site
+    async fn navigate(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        self.pacing.wait().await;
+        self.send(request).await
+    }
+
+    // subtitle tracks cost a few more requests, they're only resolved when `subtitles` is set
     pub async fn get_playlist(
         &self,
         lesson_idx: usize,
         part_idx: usize,
         hq: bool,
+        subtitles: bool,
     ) -> anyhow::Result<SchoolismVideoList> {
-        let dashboard_page = self
-            .net_client
-            .get(&format!("{}/dashboard.php", SCHOOLISM_URL))
-            .send()
-            .await
-            .context("failed to fetch dashboard page")?
-            .text()
-            .await
-            .context("failed to access text content of dashboard page")?;
-
-        let lessons = super::extractor::parse_dashboard(&dashboard_page)?;
-        let lesson = &lessons
-            .get(lesson_idx)
-            .context(format!("lesson index [{}] does not exist", lesson_idx))?;
+        Ok(self
+            .get_stream_info(lesson_idx, part_idx, hq, subtitles)
+            .await?
+            .video_list)
+    }
 
-        let lesson_page = self
-            .net_client
-            .get(&format!("{}/{}", SCHOOLISM_URL, lesson.link))
-            .send()
-            .await
-            .context("failed to fetch lesson page")?
-            .text()
+    // the resolved video list along with the manifests it was built from
+    pub async fn get_stream_info(
+        &self,
+        lesson_idx: usize,
+        part_idx: usize,
+        hq: bool,
+        subtitles: bool,
+    ) -> anyhow::Result<StreamInfo> {
+        let (lesson, page) = self.fetch_lesson(lesson_idx).await?;
+        self.get_stream_info_for(&lesson, &page, part_idx, hq, subtitles)
             .await
-            .context("failed to access text content of lesson page")?;
-
-        let playlists = super::extractor::parse_lesson(&lesson_page)
-            .context("failed to parse lesson page into playlists")?;
+    }
 
-        let playlist = playlists
-            .get(part_idx)
-            .context(format!("part index [{}] does not exist", part_idx))?;
+    // like `get_playlist`, for a lesson page that was already fetched
+    pub async fn get_playlist_for(
+        &self,
+        lesson: &super::Lesson,
+        page: &super::LessonPage,
+        part_idx: usize,
+        hq: bool,
+        subtitles: bool,
+    ) -> anyhow::Result<SchoolismVideoList> {
+        Ok(self
+            .get_stream_info_for(lesson, page, part_idx, hq, subtitles)
+            .await?
+            .video_list)
+    }
 
-        // wait a bit here to avoid timing issues
-        delay_for(Duration::from_millis(1000)).await;
+    async fn get_stream_info_for(
+        &self,
+        lesson: &super::Lesson,
+        page: &super::LessonPage,
+        part_idx: usize,
+        hq: bool,
+        subtitles: bool,
+    ) -> anyhow::Result<StreamInfo> {
+        let part = page.parts.get(part_idx).ok_or(NoSuchIndex {
+            kind: "part",
+            index: part_idx,
+        })?;
 
         // retry a few times, sometimes this call is flaky
         let mut retry_count: u32 = 0;
         let playlist = loop {
             let playlist = self
-                .net_client
-                .get(&playlist.url)
-                .send()
+                .navigate(self.net_client.get(&part.url))
                 .await
                 .context("failed to fetch playlist")?
                 .text()
@@ -138,7 +187,7 @@ impl ClientConnected {
                 anyhow::bail!("couldn't get access to playlist after 2 tries!");
             }
 
-            println!("retrying playlist retrieval");
+            eprintln!("retrying playlist retrieval");
             retry_count += 1;
         };
 
@@ -152,21 +201,131 @@ impl ClientConnected {
             .context("could not parse primary playlist")?;
 
         let secondaries = self.get_secondary_playlists(primary.clone()).await;
+        let subtitles = if subtitles {
+            self.get_subtitle_tracks(&part.url, &primary, part).await
+        } else {
+            vec![]
+        };
 
+        let primary_m3u = primary.clone();
         let primary = Primary::from_m3u(primary);
         // TODO explicitly find the correct playlist, instead of assuming index
-        let secondary = Secondary::from_m3u(secondaries[usize::from(hq)].clone()); 
-        let video_list = SchoolismVideoList::from_manifests(primary, secondary, key.0);
+        let selected = usize::from(hq);
+        let (variant, secondary_m3u) = secondaries
+            .get(selected)
+            .cloned()
+            .context("requested quality variant is not available")?;
+        let secondary = Secondary::from_m3u(secondary_m3u.clone());
+        let mut video_list = SchoolismVideoList::from_manifests(primary, secondary, key.0);
+        video_list.subtitles = subtitles;
+        video_list.lesson_url = lesson.link.clone();
+        video_list.playlist_url = part.url.clone();
+        video_list.variant_url = variant.url;
+        video_list.variant_attribs = variant.attribs;
+        video_list.hq = hq;
+        video_list.course = page.course.clone();
+        video_list.lesson_title = lesson.title.clone();
+        video_list.part_title = part.title.clone();
+
+        Ok(StreamInfo {
+            video_list,
+            primary: primary_m3u,
+            variants: secondaries
+                .into_iter()
+                .map(|(playlist, m3u)| Variant { playlist, m3u })
+                .collect(),
+            selected,
+        })
+    }
+
+    // lessons listed on the dashboard, in the order lesson indices refer to
+    pub async fn get_lessons(&self) -> anyhow::Result<Vec<super::Lesson>> {
+        let dashboard_page = self
+            .navigate(
+                self.net_client
+                    .get(&format!("{}/dashboard.php", SCHOOLISM_URL)),
+            )
+            .await
+            .context("failed to fetch dashboard page")?
+            .text()
+            .await
+            .context("failed to access text content of dashboard page")?;
+
+        super::extractor::parse_dashboard(&dashboard_page)
+    }
+
+    // navigates to the lesson page, attachment urls are resolved against it
+    pub async fn get_lesson(&self, lesson_idx: usize) -> anyhow::Result<super::LessonPage> {
+        Ok(self.fetch_lesson(lesson_idx).await?.1)
+    }
+
+    // the dashboard entry and page of a lesson, so a part can be checked against the archive
+    // and resolved with a single visit
+    pub async fn fetch_lesson(
+        &self,
+        lesson_idx: usize,
+    ) -> anyhow::Result<(super::Lesson, super::LessonPage)> {
+        let mut lessons = self.get_lessons().await?;
+        if lesson_idx >= lessons.len() {
+            return Err(NoSuchIndex {
+                kind: "lesson",
+                index: lesson_idx,
+            }
+            .into());
+        }
+        let lesson = lessons.swap_remove(lesson_idx);
+        let page = self.get_lesson_page(&lesson).await?;
+        Ok((lesson, page))
+    }
+
+    // like `get_lesson`, for a lesson already taken from the dashboard, saving a request
+    pub async fn get_lesson_page(
+        &self,
+        lesson: &super::Lesson,
+    ) -> anyhow::Result<super::LessonPage> {
+        let lesson_url = format!("{}/{}", SCHOOLISM_URL, lesson.link);
+        let lesson_page = self
+            .navigate(self.net_client.get(&lesson_url))
+            .await
+            .context("failed to fetch lesson page")?
+            .text()
+            .await
+            .context("failed to access text content of lesson page")?;
+
+        let mut page = super::extractor::parse_lesson(&lesson_page)
+            .context("failed to parse lesson page into playlists")?;
+
+        for attachment in &mut page.attachments {
+            attachment.url = resolve_url(&lesson_url, &attachment.url)?;
+        }
+
+        Ok(page)
+    }
+
+    pub async fn download_attachment(
+        &self,
+        attachment: &super::LessonAttachment,
+    ) -> anyhow::Result<Vec<u8>> {
+        let bytes = self
+            .send(self.net_client.get(&attachment.url))
+            .await
+            .context(format!("could not get attachment [{}]", attachment.url))?
+            .error_for_status()
+            .context(format!("attachment request failed [{}]", attachment.url))?
+            .bytes()
+            .await
+            .context("could not get attachment bytes")?;
 
-        Ok(video_list)
+        Ok(bytes.to_vec())
     }
 
     // should be done after navigating to a lesson
     async fn get_key(&self) -> anyhow::Result<Key> {
         let keytime_resp = self
-            .net_client
-            .get("https://www.schoolism.com/video-html/key-time.php")
-            .send()
+            .navigate(
+                self.net_client
+                    .get("https://www.schoolism.com/video-html/key-time.php"),
+            )
             .await
             .context("failed to send request to key-time")?;
 
@@ -175,9 +334,10 @@ impl ClientConnected {
         }
 
         let key_resp = self
-            .net_client
-            .get("https://www.schoolism.com/video-html/key.php")
-            .send()
+            .navigate(
+                self.net_client
+                    .get("https://www.schoolism.com/video-html/key.php"),
+            )
             .await
             .context("failed to send request to access key response")?;
 
@@ -194,21 +354,23 @@ impl ClientConnected {
         Ok(Key(key))
     }
 
-    async fn get_secondary_playlists(&self, playlist: M3U) -> Vec<M3U> {
-        let secondary_urls = playlist.subplaylists.into_iter().map(|it| it.url);
-        let results: Vec<JoinHandle<Result<M3U>>> = secondary_urls
+    // variants are returned alongside their playlist, in primary playlist order
+    async fn get_secondary_playlists(&self, playlist: M3U) -> Vec<(SubPlaylist, M3U)> {
+        let results: Vec<JoinHandle<Result<(SubPlaylist, M3U)>>> = playlist
+            .subplaylists
+            .into_iter()
             .map(|it| {
                 let net_client = self.net_client.clone();
+                let request_limiter = self.request_limiter.clone();
                 tokio::spawn(async move {
-                    net_client
-                        .get(&it)
-                        .send()
+                    let m3u = send_limited(&request_limiter, net_client.get(&it.url))
                         .await
                         .context("could not get secondary playlist")?
                         .text()
                         .await
                         .context("could not get secondary playlist text")?
-                        .parse()
+                        .parse()?;
+                    Ok((it, m3u))
                 })
             })
             .collect();
@@ -218,10 +380,193 @@ impl ClientConnected {
             .into_iter()
             .partition(Result::is_ok);
 
-        succ.into_iter()
-            .flat_map(Result::unwrap)
+        succ.into_iter().flat_map(Result::unwrap).collect()
+    }
+
+    // caption tracks come either from `EXT-X-MEDIA` entries in the primary playlist
+    // or from the player config on the lesson page, the playlist ones take precedence
+    async fn get_subtitle_tracks(
+        &self,
+        primary_url: &str,
+        primary: &M3U,
+        part: &super::LessonPart,
+    ) -> Vec<SubtitleTrack> {
+        let mut tracks = vec![];
+
+        for media in primary.subtitle_media() {
+            let uri = match &media.uri {
+                Some(uri) => uri,
+                None => continue,
+            };
+
+            let track = async {
+                let url = resolve_url(primary_url, uri)?;
+                let segments = self.get_subtitle_segments(&url).await?;
+                Ok::<_, anyhow::Error>(SubtitleTrack {
+                    name: media.attribs.get("NAME").cloned(),
+                    language: media.attribs.get("LANGUAGE").cloned(),
+                    segments,
+                })
+            };
+
+            match track.await {
+                Ok(track) => tracks.push(track),
+                Err(e) => eprintln!("skipping subtitle track [{}]: {:?}", uri, e),
+            }
+        }
+
+        for subtitle in &part.subtitles {
+            let known = tracks
+                .iter()
+                .any(|it| it.language.is_some() && it.language == subtitle.language);
+            if known {
+                continue;
+            }
+
+            match self.get_subtitle_segments(&subtitle.url).await {
+                Ok(segments) => tracks.push(SubtitleTrack {
+                    name: subtitle.label.clone(),
+                    language: subtitle.language.clone(),
+                    segments,
+                }),
+                Err(e) => eprintln!("skipping subtitle track [{}]: {:?}", subtitle.url, e),
+            }
+        }
+
+        tracks
+    }
+
+    // a caption url is either a webvtt segment playlist or a plain webvtt file
+    async fn get_subtitle_segments(&self, url: &str) -> anyhow::Result<Vec<String>> {
+        if !url.split('?').next().unwrap_or_default().ends_with(".m3u8") {
+            return Ok(vec![url.into()]);
+        }
+
+        let playlist: M3U = self
+            .send(self.net_client.get(url))
+            .await
+            .context("could not get subtitle playlist")?
+            .text()
+            .await
+            .context("could not get subtitle playlist text")?
+            .parse()
+            .context("could not parse subtitle playlist")?;
+
+        playlist
+            .tracklist
+            .iter()
+            .map(|it| resolve_url(url, &it.name))
             .collect()
     }
+
+    // downloads every segment of a track and stitches them into a single webvtt file
+    pub async fn download_subtitles(
+        &self,
+        list: &SchoolismVideoList,
+        track: &SubtitleTrack,
+    ) -> anyhow::Result<String> {
+        let mut segments = vec![];
+        for url in &track.segments {
+            let segment = self
+                .download_media(list, url)
+                .await
+                .context(format!("could not get subtitle segment [{}]", url))?
+                .text()
+                .await
+                .context("could not get subtitle segment text")?;
+            segments.push(segment);
+        }
+
+        crate::subtitles::stitch_webvtt(&segments)
+    }
+}
+
+impl ClientConnected {
+    // media requests go through the logged in session, with the headers the site's player
+    // would send, since cdns may check signed cookies or the referer
+    // these are not counted against the request limiter, segments are throttled by bandwidth
+    pub async fn download_media(
+        &self,
+        list: &SchoolismVideoList,
+        url: &str,
+    ) -> anyhow::Result<reqwest::Response> {
+        let resp = self
+            .net_client
+            .get(url)
+            .header(reqwest::header::REFERER, list.referer())
+            .header(reqwest::header::ORIGIN, SCHOOLISM_URL)
+            .send()
+            .await
+            .context(format!("failed to send media request [{}]", url))?;
+
+        let status = resp.status();
+        if super::refresh::is_expired(status, &[]) {
+            return Err(MediaExpired {
+                url: url.into(),
+                status,
+            }
+            .into());
+        }
+
+        if !status.is_success() {
+            return Err(crate::http::StatusError {
+                url: url.into(),
+                status,
+            }
+            .into());
+        }
+
+        Ok(resp)
+    }
+}
+
+// signed media urls (or the session they're tied to) are no longer valid
+#[derive(Debug)]
+pub struct MediaExpired {
+    pub url: String,
+    pub status: reqwest::StatusCode,
+}
+
+impl std::fmt::Display for MediaExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "media url expired [{}]: [{}]", self.url, self.status)
+    }
+}
+
+impl std::error::Error for MediaExpired {}
+
+// a lesson or part index past the end of what the site lists
+#[derive(Debug)]
+pub struct NoSuchIndex {
+    pub kind: &'static str, // "lesson" or "part"
+    pub index: usize,
+}
+
+impl std::fmt::Display for NoSuchIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} index [{}] does not exist", self.kind, self.index)
+    }
+}
+
+impl std::error::Error for NoSuchIndex {}
+
+async fn send_limited(
+    limiter: &Option<Arc<RateLimiter>>,
+    request: reqwest::RequestBuilder,
+) -> reqwest::Result<reqwest::Response> {
+    if let Some(limiter) = limiter {
+        limiter.acquire(1).await;
+    }
+    request.send().await
+}
+
+// playlist entries may be relative to the playlist they're listed in
+fn resolve_url(base: &str, uri: &str) -> anyhow::Result<String> {
+    let base = reqwest::Url::parse(base).context(format!("invalid base url: [{}]", base))?;
+    let url = base
+        .join(uri)
+        .context(format!("could not resolve [{}] against [{}]", uri, base))?;
+    Ok(url.into_string())
 }
 
 fn retrieve_base_url_for_playlist(playlist_url: &str) -> anyhow::Result<String> {
@@ -235,10 +580,49 @@ fn retrieve_base_url_for_playlist(playlist_url: &str) -> anyhow::Result<String>
     Ok(url)
 }
 
+#[derive(Serialize)]
 pub struct SchoolismVideoList {
+    #[serde(serialize_with = "crate::util::serialize_hex")]
     pub key: Vec<u8>,
+    #[serde(serialize_with = "crate::util::serialize_hex")]
     pub iv: Vec<u8>,
-    pub files: Vec<String>, // full url including domain
+    pub files: Vec<String>,  // full url including domain
+    pub durations: Vec<f64>, // per file, from `EXTINF`
+    pub subtitles: Vec<SubtitleTrack>,
+
+    // where the list came from
+    pub lesson_url: String, // `watchLesson.php` link, relative to the site
+    pub playlist_url: String,
+    pub variant_url: String,
+    pub variant_attribs: HashMap<String, String>,
+    pub hq: bool,
+
+    pub course: Option<String>,
+    pub lesson_title: Option<String>,
+    pub part_title: Option<String>,
+}
+
+// everything resolved for a part, for tools that do their own downloading
+#[derive(Serialize)]
+pub struct StreamInfo {
+    pub video_list: SchoolismVideoList,
+    pub primary: M3U,           // master playlist, lists every quality variant
+    pub variants: Vec<Variant>, // every variant that could be fetched, in master playlist order
+    pub selected: usize,        // index of the variant `video_list` was built from
+}
+
+// a quality variant of the master playlist, with the segments and key
+#[derive(Serialize)]
+pub struct Variant {
+    pub playlist: SubPlaylist,
+    pub m3u: M3U,
+}
+
+#[derive(Serialize, Clone)]
+pub struct SubtitleTrack {
+    pub name: Option<String>,
+    pub language: Option<String>,
+    pub segments: Vec<String>, // full url including domain
 }
 
 impl SchoolismVideoList {
@@ -259,8 +643,41 @@ impl SchoolismVideoList {
             key,
             iv: secondary.iv,
             files, //
+            durations: secondary.durations,
+            subtitles: vec![],
+            lesson_url: String::new(),
+            playlist_url: String::new(),
+            variant_url: String::new(),
+            variant_attribs: HashMap::new(),
+            hq: false,
+            course: None,
+            lesson_title: None,
+            part_title: None,
         }
     }
+
+    // the lesson page hosting the player
+    pub fn referer(&self) -> String {
+        format!("{}/{}", SCHOOLISM_URL, self.lesson_url)
+    }
+
+    pub fn archive_id(&self) -> String {
+        crate::archive::part_id(&self.lesson_url, &self.playlist_url, self.hq)
+    }
+
+    pub fn total_duration(&self) -> f64 {
+        self.durations.iter().sum()
+    }
+
+    // peak bitrate the variant declares, in bits per second
+    pub fn bandwidth(&self) -> Option<u64> {
+        self.variant_attribs.get("BANDWIDTH")?.trim().parse().ok()
+    }
+
+    // output size from the declared bitrate and duration, an upper bound more than a guess
+    pub fn estimated_size(&self) -> Option<u64> {
+        Some((self.bandwidth()? as f64 / 8.0 * self.total_duration()) as u64)
+    }
 }
 
 struct Primary {
@@ -276,13 +693,23 @@ impl Primary {
 struct Secondary {
     iv: Vec<u8>,
     files: Vec<String>,
+    durations: Vec<f64>,
 }
 
 impl Secondary {
     fn from_m3u(m3u: M3U) -> Self {
         let iv = m3u.key_info.unwrap().iv.clone();
         let iv = crate::util::decode_hex(&iv).unwrap();
+        let durations = m3u
+            .tracklist
+            .iter()
+            .map(|it| it.duration().unwrap_or_default())
+            .collect();
         let files = m3u.tracklist.into_iter().map(|it| it.name).collect();
-        Self { iv, files }
+        Self {
+            iv,
+            files,
+            durations,
+        }
     }
 }
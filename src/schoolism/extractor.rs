@@ -2,12 +2,16 @@ use anyhow::Context;
 use once_cell::sync::Lazy;
 use scraper::{Html, Selector};
 
-static VIDEO_LIST_START_RE: Lazy<regex::Regex> =
-    Lazy::new(|| regex::Regex::new(r"allVideos").unwrap());
+use crate::jsliteral::JsValue;
+
+// file types linked from lesson pages that are worth keeping
+const ATTACHMENT_EXTENSIONS: [&str; 13] = [
+    "pdf", "zip", "rar", "7z", "psd", "jpg", "jpeg", "png", "gif", "webp", "doc", "docx", "txt",
+];
 
-// no backreferences :V
-static PLAYLIST_URL_RE: Lazy<regex::Regex> =
-    Lazy::new(|| regex::Regex::new(r#"src\s*:\s*["'](https://[^"']+)["']\s*,"#).unwrap());
+// the assignment, not just any mention, so we land right before the literal
+static VIDEO_LIST_START_RE: Lazy<regex::Regex> =
+    Lazy::new(|| regex::Regex::new(r"allVideos\s*[=:]\s*").unwrap());
 
 pub fn parse_dashboard(page: &str) -> anyhow::Result<Vec<super::Lesson>> {
     let document = Html::parse_document(page);
@@ -28,7 +32,10 @@ pub fn parse_dashboard(page: &str) -> anyhow::Result<Vec<super::Lesson>> {
     let links = main.select(&lesson_selector);
 
     let (links, errors): (Vec<_>, Vec<_>) = links
-        .map(|l| l.value().attr("href"))
+        .map(|l| {
+            let title = l.text().collect::<String>().trim().to_string();
+            l.value().attr("href").map(|href| (href, title))
+        })
         .partition(Option::is_some);
 
     if errors.len() > 0 {
@@ -36,38 +43,277 @@ pub fn parse_dashboard(page: &str) -> anyhow::Result<Vec<super::Lesson>> {
             "[{}] errors found while parsing dashboard links",
             errors.len()
         );
-        println!("{}", msg);
+        eprintln!("{}", msg);
     }
 
     let ret: Vec<_> = links
         .into_iter()
         .map(Option::unwrap)
-        .filter(|&link| link.starts_with("watchLesson.php"))
+        .filter(|(link, _)| link.starts_with("watchLesson.php"))
         .enumerate()
-        .map(|(no, link)| super::Lesson {
+        .map(|(no, (link, title))| super::Lesson {
             _no: no,
             link: link.into(),
+            title: Some(title).filter(|it| !it.is_empty()),
         })
         .collect();
 
     Ok(ret)
 }
 
-pub fn parse_lesson(page: &str) -> anyhow::Result<Vec<super::LessonPart>> {
-    // find the allVideos js array, and map only the the "src" field
-    // assume the urls are sorted
-    let captures = VIDEO_LIST_START_RE
-        .find(page)
+pub fn parse_lesson(page: &str) -> anyhow::Result<super::LessonPage> {
+    let parts = parse_lesson_parts(page)?;
+    let attachments = parse_lesson_attachments(page)?;
+    let course = parse_course_title(page)?;
+
+    Ok(super::LessonPage {
+        course,
+        parts,
+        attachments,
+    })
+}
+
+// page titles look like `Schoolism - <course>`
+fn parse_course_title(page: &str) -> anyhow::Result<Option<String>> {
+    let document = Html::parse_document(page);
+
+    let title_selector = Selector::parse("title")
+        .map_err(|e| anyhow::anyhow!("{:?}", e))
+        .context("could not create title selector for html")?;
+
+    let title = document
+        .select(&title_selector)
+        .next()
+        .map(|it| it.text().collect::<String>());
+
+    Ok(title
+        .map(|it| {
+            // only the spaced separators, course names have hyphens of their own
+            it.split(" | ")
+                .flat_map(|part| part.split(" - "))
+                .map(str::trim)
+                .filter(|part| !part.is_empty() && !part.eq_ignore_ascii_case("schoolism"))
+                .collect::<Vec<_>>()
+                .join(" - ")
+        })
+        .filter(|it| !it.is_empty()))
+}
+
+// links are returned as found on the page, they may be relative to the lesson url
+fn parse_lesson_attachments(page: &str) -> anyhow::Result<Vec<super::LessonAttachment>> {
+    let document = Html::parse_document(page);
+
+    let link_selector = Selector::parse("a[href]")
+        .map_err(|e| anyhow::anyhow!("{:?}", e))
+        .context("could not create link selector for html")?;
+
+    let mut attachments: Vec<super::LessonAttachment> = vec![];
+    for link in document.select(&link_selector) {
+        let href = link.value().attr("href").unwrap_or_default().trim();
+        if href.is_empty() || href.starts_with('#') || href.starts_with("javascript:") {
+            continue;
+        }
+
+        let path = href.split(['?', '#']).next().unwrap_or_default();
+        let file_name = path.rsplit('/').next().unwrap_or_default();
+        let extension = file_name
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_lowercase())
+            .unwrap_or_default();
+
+        let download_attr = link.value().attr("download");
+        if download_attr.is_none() && !ATTACHMENT_EXTENSIONS.contains(&extension.as_str()) {
+            continue;
+        }
+
+        if attachments.iter().any(|it| it.url == href) {
+            continue;
+        }
+
+        let name = download_attr
+            .filter(|it| !it.trim().is_empty())
+            .unwrap_or(file_name);
+        let name = if name.is_empty() {
+            link.text().collect::<String>().trim().to_string()
+        } else {
+            name.to_string()
+        };
+
+        attachments.push(super::LessonAttachment {
+            url: href.into(),
+            name,
+        });
+    }
+
+    Ok(attachments)
+}
+
+fn parse_lesson_parts(page: &str) -> anyhow::Result<Vec<super::LessonPart>> {
+    // find the allVideos js array and parse it as a literal, entries are kept in page order
+    let video_list = VIDEO_LIST_START_RE
+        .find_iter(page)
+        .filter_map(|m| crate::jsliteral::parse_prefix(&page[m.end()..]).ok())
+        .map(|(value, _)| value)
+        .find(|value| value.as_array().is_some())
         .context("can't find video list on page response")?;
-    let i = captures.start();
-    let narrow = &page[i..];
-    let video_list = crate::util::matching_bracket_substring(narrow, '[')
-        .context("failed to find matching bracket substring for video url list")?;
-
-    let urls: Vec<_> = PLAYLIST_URL_RE
-        .captures_iter(video_list)
-        .map(|it| super::LessonPart { url: it[1].into() })
+
+    video_list
+        .as_array()
+        .unwrap_or_default()
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            parse_lesson_part(entry).context(format!("failed to parse video entry [{}]", i))
+        })
+        .collect()
+}
+
+fn parse_lesson_part(entry: &JsValue) -> anyhow::Result<super::LessonPart> {
+    // src is either on the entry itself or in a video.js style sources list
+    let url = entry
+        .get("src")
+        .or_else(|| entry.get("sources").and_then(|it| it.find("src")))
+        .and_then(JsValue::as_str)
+        .context("video entry has no src field")?
+        .into();
+
+    let title = entry.get("title").and_then(JsValue::as_str).map(Into::into);
+    let duration = entry.get("duration").and_then(JsValue::as_f64);
+    let poster = entry
+        .get("poster")
+        .and_then(JsValue::as_str)
+        .map(Into::into);
+
+    let subtitles = ["subtitles", "captions", "tracks"]
+        .iter()
+        .filter_map(|key| entry.get(key))
+        .filter_map(JsValue::as_array)
+        .flatten()
+        .filter_map(parse_subtitle)
         .collect();
 
-    Ok(urls)
+    Ok(super::LessonPart {
+        url,
+        title,
+        duration,
+        poster,
+        subtitles,
+    })
+}
+
+fn parse_subtitle(track: &JsValue) -> Option<super::LessonSubtitle> {
+    if let Some(url) = track.as_str() {
+        return Some(super::LessonSubtitle {
+            url: url.into(),
+            label: None,
+            language: None,
+        });
+    }
+
+    // video.js tracks may also carry chapters or thumbnails, only keep text tracks
+    if let Some(kind) = track.get("kind").and_then(JsValue::as_str) {
+        if kind != "subtitles" && kind != "captions" {
+            return None;
+        }
+    }
+
+    let field = |keys: &[&str]| {
+        keys.iter()
+            .filter_map(|key| track.get(key))
+            .find_map(JsValue::as_str)
+            .map(String::from)
+    };
+
+    Some(super::LessonSubtitle {
+        url: field(&["src", "file", "url"])?,
+        label: field(&["label", "title"]),
+        language: field(&["srclang", "language", "lang"]),
+    })
+}
+
+#[cfg(test)]
+mod extractor_tests {
+    use super::*;
+
+    const LESSON_PAGE: &str = r#"
+        <title>Schoolism - Fundamentals of Lighting</title>
+        <script>
+        var allVideos = [
+            {
+                sources: [{ type: "application/x-mpegURL", src: "https://cdn.example.com/p1/index.m3u8" }],
+                title: "Part 1: \"Gesture\" [intro]",
+                duration: "612.5",
+                poster: 'https://cdn.example.com/p1.jpg',
+                tracks: [{ kind: "captions", src: "https://cdn.example.com/p1/en.m3u8", srclang: "en", label: "English" }],
+            },
+            // src: "https://cdn.example.com/commented-out.m3u8",
+            { src: 'https://cdn.example.com/p2/index.m3u8', title: 'Part 2 – Ünïcode', duration: 300 },
+        ];
+        function play(i) { return allVideos[i]; }
+        </script>
+    "#;
+
+    #[test]
+    fn parses_every_video_entry_in_order() {
+        let page = parse_lesson(LESSON_PAGE).unwrap();
+        assert_eq!(page.course.as_deref(), Some("Fundamentals of Lighting"));
+
+        let parts = page.parts;
+        assert_eq!(parts.len(), 2);
+
+        assert_eq!(parts[0].url, "https://cdn.example.com/p1/index.m3u8");
+        assert_eq!(
+            parts[0].title.as_deref(),
+            Some("Part 1: \"Gesture\" [intro]")
+        );
+        assert_eq!(parts[0].duration, Some(612.5));
+        assert_eq!(
+            parts[0].poster.as_deref(),
+            Some("https://cdn.example.com/p1.jpg")
+        );
+        assert_eq!(parts[0].subtitles.len(), 1);
+        assert_eq!(parts[0].subtitles[0].language.as_deref(), Some("en"));
+
+        assert_eq!(parts[1].url, "https://cdn.example.com/p2/index.m3u8");
+        assert_eq!(parts[1].title.as_deref(), Some("Part 2 – Ünïcode"));
+        assert!(parts[1].subtitles.is_empty());
+    }
+
+    #[test]
+    fn collects_attachment_links() {
+        let page = format!(
+            r#"<div class="resources">
+                <a href="files/brief.pdf">Assignment brief</a>
+                <a href="https://cdn.example.com/ref/pose.JPG?sig=abc">Reference</a>
+                <a href="download.php?id=4" download="palette.aso">Palette</a>
+                <a href="files/brief.pdf">Assignment brief again</a>
+                <a href="watchLesson.php?id=2">Next lesson</a>
+                <a href="javascript:void(0)">Play</a>
+            </div>{}"#,
+            LESSON_PAGE
+        );
+
+        let attachments = parse_lesson(&page).unwrap().attachments;
+        let names: Vec<_> = attachments.iter().map(|it| it.name.as_str()).collect();
+        assert_eq!(names, vec!["brief.pdf", "pose.JPG", "palette.aso"]);
+        assert_eq!(
+            attachments[1].url,
+            "https://cdn.example.com/ref/pose.JPG?sig=abc"
+        );
+    }
+
+    #[test]
+    fn keeps_hyphens_in_course_titles() {
+        let page =
+            "<html><head><title>Schoolism - Self-Portraits | Schoolism</title></head></html>";
+        assert_eq!(
+            parse_course_title(page).unwrap().as_deref(),
+            Some("Self-Portraits")
+        );
+    }
+
+    #[test]
+    fn missing_video_list_is_an_error() {
+        assert!(parse_lesson("<html>nothing here</html>").is_err());
+    }
 }
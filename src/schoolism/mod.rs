@@ -1,11 +1,43 @@
 pub mod client;
 mod extractor;
+pub mod pacing;
+pub mod refresh;
 
 pub struct Lesson {
     _no: usize,
     link: String,
+    pub title: Option<String>,
+}
+
+pub struct LessonPage {
+    pub course: Option<String>,
+    pub parts: Vec<LessonPart>,
+    pub attachments: Vec<LessonAttachment>,
 }
 
 pub struct LessonPart {
     url: String,
+    pub title: Option<String>,
+    pub duration: Option<f64>, // seconds
+    pub poster: Option<String>,
+    pub subtitles: Vec<LessonSubtitle>,
+}
+
+pub struct LessonSubtitle {
+    pub url: String,
+    pub label: Option<String>,
+    pub language: Option<String>,
+}
+
+// assignment briefs, pdfs and reference images linked from the lesson page
+pub struct LessonAttachment {
+    pub url: String,
+    pub name: String,
+}
+
+impl Lesson {
+    // stable id of one of this lesson's parts, see `archive::part_id`
+    pub fn part_id(&self, part: &LessonPart, hq: bool) -> String {
+        crate::archive::part_id(&self.link, &part.url, hq)
+    }
 }
@@ -0,0 +1,84 @@
+use rand::Rng;
+use std::sync::Mutex;
+use tokio::time::{delay_until, Duration, Instant};
+
+// spacing between navigation requests (dashboard, lesson, playlist, key), so a bulk
+// download reads like someone clicking through lessons rather than a burst of requests
+// every request waits a random delay in [min, max] after the previous one
+pub struct Pacing {
+    min: Duration,
+    max: Duration,
+    next_slot: Mutex<Option<Instant>>,
+}
+
+impl Pacing {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max: max.max(min),
+            next_slot: Mutex::new(None),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(Duration::from_millis(0), Duration::from_millis(0))
+    }
+
+    pub async fn wait(&self) {
+        let delay = self.delay();
+
+        // reserve a slot before sleeping so concurrent callers queue up behind us
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let now = Instant::now();
+            let slot = next_slot.map_or(now, |it| it.max(now));
+            *next_slot = Some(slot + delay);
+            slot
+        };
+
+        delay_until(slot).await;
+    }
+
+    fn delay(&self) -> Duration {
+        if self.max == self.min {
+            return self.min;
+        }
+
+        let millis = rand::thread_rng().gen_range(self.min.as_millis()..=self.max.as_millis());
+        Duration::from_millis(millis as u64)
+    }
+}
+
+// matches the fixed one second wait we used to do before fetching playlists
+impl Default for Pacing {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(1))
+    }
+}
+
+#[cfg(test)]
+mod pacing_tests {
+    use super::*;
+
+    #[test]
+    fn delays_stay_within_bounds() {
+        let pacing = Pacing::new(Duration::from_millis(10), Duration::from_millis(20));
+        for _ in 0..100 {
+            let delay = pacing.delay();
+            assert!(delay >= Duration::from_millis(10) && delay <= Duration::from_millis(20));
+        }
+    }
+
+    #[tokio::test]
+    async fn first_request_is_immediate_and_later_ones_are_spaced() {
+        let pacing = Pacing::new(Duration::from_millis(50), Duration::from_millis(50));
+
+        let start = Instant::now();
+        pacing.wait().await;
+        assert!(start.elapsed() < Duration::from_millis(40));
+
+        pacing.wait().await;
+        pacing.wait().await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,296 @@
+use anyhow::Context;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+
+use super::client::{ClientConnected, MediaExpired, SchoolismVideoList};
+use crate::decryption::Cipher;
+use crate::download::{DownloadEvent, EventCallback};
+use crate::ratelimit::RateLimiter;
+
+// a lesson part whose signed playlist, segment urls and key can be re-resolved while it's
+// downloading. long hq downloads can outlive the signatures, when a segment request comes
+// back expired the lesson -> playlist -> key handshake is redone and the remaining segments
+// are fetched from the fresh list
+pub struct RefreshablePart {
+    client: Arc<ClientConnected>,
+    lesson_idx: usize,
+    part_idx: usize,
+    hq: bool,
+    max_refreshes: u32,
+    validate: bool,
+    events: Option<EventCallback>,
+    current: Generations<SchoolismVideoList>,
+}
+
+// a value that is replaced as a whole, numbered so that tasks which all noticed the same
+// stale generation share a single refresh instead of each running their own
+struct Generations<T> {
+    current: RwLock<(u64, Arc<T>)>,
+    refresh_lock: tokio::sync::Mutex<()>,
+}
+
+impl<T> Generations<T> {
+    fn new(value: T) -> Self {
+        Self {
+            current: RwLock::new((0, Arc::new(value))),
+            refresh_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn current(&self) -> (u64, Arc<T>) {
+        let current = self.current.read().unwrap();
+        (current.0, current.1.clone())
+    }
+
+    // replaces `seen_generation` with what `resolve` returns, unless another task already
+    // replaced it while this one was waiting
+    async fn refresh<F, Fut>(&self, seen_generation: u64, resolve: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(Arc<T>) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let _guard = self.refresh_lock.lock().await;
+
+        let (generation, old) = self.current();
+        if generation != seen_generation {
+            return Ok(());
+        }
+
+        let fresh = resolve(old).await?;
+        *self.current.write().unwrap() = (generation + 1, Arc::new(fresh));
+        Ok(())
+    }
+}
+
+// expired signatures come back as 403 or 410, or from some cdns as a 200 with an xml error
+// document, so the body is checked as well once it's been read
+pub(crate) fn is_expired(status: reqwest::StatusCode, body: &[u8]) -> bool {
+    status == reqwest::StatusCode::FORBIDDEN
+        || status == reqwest::StatusCode::GONE
+        || (body.starts_with(b"<") && String::from_utf8_lossy(body).contains("AccessDenied"))
+}
+
+impl RefreshablePart {
+    pub async fn resolve(
+        client: Arc<ClientConnected>,
+        lesson_idx: usize,
+        part_idx: usize,
+        hq: bool,
+        subtitles: bool,
+    ) -> anyhow::Result<Self> {
+        let list = client
+            .get_playlist(lesson_idx, part_idx, hq, subtitles)
+            .await?;
+        Ok(Self::new(client, lesson_idx, part_idx, hq, list))
+    }
+
+    // for a list that was already resolved, refreshes still go through the lesson index
+    pub fn new(
+        client: Arc<ClientConnected>,
+        lesson_idx: usize,
+        part_idx: usize,
+        hq: bool,
+        list: SchoolismVideoList,
+    ) -> Self {
+        Self {
+            client,
+            lesson_idx,
+            part_idx,
+            hq,
+            max_refreshes: 3,
+            validate: true,
+            events: None,
+            current: Generations::new(list),
+        }
+    }
+
+    pub fn with_max_refreshes(mut self, max_refreshes: u32) -> Self {
+        self.max_refreshes = max_refreshes;
+        self
+    }
+
+    // check decrypted segments are well formed transport streams before handing them out
+    pub fn with_validation(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
+    // reports invalid segments and expired urls, the downloader reports everything else
+    pub fn with_events(mut self, events: EventCallback) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn list(&self) -> Arc<SchoolismVideoList> {
+        self.current.current().1
+    }
+
+    fn emit(&self, event: DownloadEvent) {
+        if let Some(events) = &self.events {
+            events(&event);
+        }
+    }
+
+    // downloads and decrypts a segment by index, refreshing the part if its urls expired
+    // segments that don't decrypt to a valid transport stream are downloaded again, and if
+    // that doesn't help the key is re-fetched, since a stale key is the usual culprit
+    pub async fn download_segment(
+        &self,
+        idx: usize,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut refreshes = 0;
+        let mut invalid = 0;
+
+        loop {
+            let (generation, list) = self.current.current();
+            let url = list
+                .files
+                .get(idx)
+                .context(format!("segment index [{}] does not exist", idx))?;
+
+            match self.fetch(&list, url, rate_limiter).await {
+                Ok(mut bytes) => {
+                    let cipher = Cipher::from_list(&list);
+                    let problem = match cipher.decrypt(&mut bytes) {
+                        Ok(decrypted) if !self.validate => return Ok(decrypted.to_vec()),
+                        Ok(decrypted) => {
+                            let report = crate::ts::validate_segment(decrypted);
+                            if report.is_valid() {
+                                return Ok(decrypted.to_vec());
+                            }
+                            report.to_string()
+                        }
+                        Err(e) => format!("{:#}", e),
+                    };
+
+                    invalid += 1;
+                    self.emit(DownloadEvent::SegmentInvalid {
+                        idx,
+                        attempt: invalid,
+                        problem: problem.clone(),
+                    });
+
+                    match invalid {
+                        1 => {}
+                        2 if refreshes < self.max_refreshes => {
+                            self.refresh(generation).await?;
+                            refreshes += 1;
+                        }
+                        _ => anyhow::bail!("segment [{}] is not a valid stream: {}", idx, problem),
+                    }
+                }
+                Err(e) if e.downcast_ref::<MediaExpired>().is_some() => {
+                    if refreshes >= self.max_refreshes {
+                        return Err(e.context(format!(
+                            "segment [{}] still expired after [{}] refreshes",
+                            idx, refreshes
+                        )));
+                    }
+
+                    self.emit(DownloadEvent::UrlsExpired { idx });
+                    self.refresh(generation).await?;
+                    refreshes += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn fetch(
+        &self,
+        list: &SchoolismVideoList,
+        url: &str,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let resp = self.client.download_media(list, url).await?;
+        let status = resp.status();
+        let bytes = crate::download::read_body(resp, url, rate_limiter).await?;
+
+        if is_expired(status, &bytes) {
+            return Err(MediaExpired {
+                url: url.into(),
+                status,
+            }
+            .into());
+        }
+
+        Ok(bytes)
+    }
+
+    async fn refresh(&self, seen_generation: u64) -> anyhow::Result<()> {
+        self.current
+            .refresh(seen_generation, |old| async move {
+                // subtitles don't expire with the video, keep the ones already resolved
+                let mut list = self
+                    .client
+                    .get_playlist(self.lesson_idx, self.part_idx, self.hq, false)
+                    .await
+                    .context("failed to refresh expired playlist")?;
+                list.subtitles = old.subtitles.clone();
+
+                // segments are addressed by index, so the fresh list has to line up with the old one
+                if list.files.len() != old.files.len() {
+                    anyhow::bail!(
+                        "refreshed playlist has [{}] segments, expected [{}]",
+                        list.files.len(),
+                        old.files.len()
+                    );
+                }
+                Ok(list)
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod refresh_tests {
+    use super::*;
+    use reqwest::StatusCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn classifies_expired_responses() {
+        assert!(is_expired(StatusCode::FORBIDDEN, b""));
+        assert!(is_expired(StatusCode::GONE, b""));
+        assert!(is_expired(
+            StatusCode::OK,
+            b"<?xml version=\"1.0\"?><Error><Code>AccessDenied</Code></Error>"
+        ));
+
+        assert!(!is_expired(StatusCode::OK, &[0x47, 0x40, 0x00]));
+        assert!(!is_expired(StatusCode::NOT_FOUND, b""));
+        // a segment that merely mentions it isn't an error document
+        assert!(!is_expired(StatusCode::OK, b"AccessDenied"));
+    }
+
+    #[tokio::test]
+    async fn concurrent_refreshes_collapse_into_one() {
+        let generations = Generations::new(0u32);
+        let resolves = AtomicUsize::new(0);
+
+        let (seen, _) = generations.current();
+        let refresh = || {
+            generations.refresh(seen, |old| {
+                resolves.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    tokio::time::delay_for(tokio::time::Duration::from_millis(10)).await;
+                    Ok(*old + 1)
+                }
+            })
+        };
+        let (a, b, c) = futures::join!(refresh(), refresh(), refresh());
+        a.and(b).and(c).unwrap();
+
+        assert_eq!(resolves.load(Ordering::SeqCst), 1);
+        let (generation, value) = generations.current();
+        assert_eq!((generation, *value), (1, 1));
+
+        // a task that saw the new generation refreshes again
+        generations
+            .refresh(generation, |old| async move { Ok(*old + 1) })
+            .await
+            .unwrap();
+        assert_eq!(*generations.current().1, 2);
+    }
+}
@@ -0,0 +1,412 @@
+use anyhow::Context;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::cancel::CancellationToken;
+use crate::queue::{Job, JobQueue};
+use crate::schoolism::client::{ClientConnected, NoSuchIndex};
+
+// local json api for `selene serve`, so scripts and web uis can browse lessons and manage
+// downloads through one logged in session. jobs live in the persistent queue, a worker
+// next to the server runs them and reports progress here, which is only kept in memory
+//
+//   GET    /lessons       lessons on the dashboard
+//   GET    /lessons/{n}   parts and attachments of a lesson
+//   GET    /jobs          queued jobs, with progress for running ones
+//   POST   /jobs          queue a part, {"lesson": n, "part": n, "hq": bool}
+//   GET    /jobs/{id}     a single job
+//   DELETE /jobs/{id}     cancel a running job, or remove a waiting one
+
+#[derive(Serialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct JobProgress {
+    pub segments_written: usize,
+    pub segments_total: usize,
+    pub bytes_written: u64,
+}
+
+struct RunningJob {
+    cancellation: CancellationToken,
+    progress: JobProgress,
+}
+
+// shared by the api handlers and the worker, queue changes go through `with_queue` so they
+// happen one at a time and off the async threads
+pub struct ControlState {
+    queue: Mutex<JobQueue>,
+    running: Mutex<HashMap<u64, RunningJob>>,
+    added: Notify,
+}
+
+impl ControlState {
+    pub fn new(queue: JobQueue) -> Self {
+        Self {
+            queue: Mutex::new(queue),
+            running: Mutex::new(HashMap::new()),
+            added: Notify::new(),
+        }
+    }
+
+    // runs `change` on the blocking pool while holding the queue
+    pub async fn with_queue<T, F>(self: &Arc<Self>, change: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&Self, &JobQueue) -> anyhow::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let state = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            let queue = state.queue.lock().unwrap();
+            change(&state, &queue)
+        })
+        .await
+        .context("queue task panicked")?
+    }
+
+    // claims the next pending job and registers it as running in one step, so a delete
+    // through the api either removes it before it starts or cancels it. the returned token
+    // is cancelled when the job is deleted
+    pub async fn claim_next(
+        self: &Arc<Self>,
+        session: &CancellationToken,
+    ) -> anyhow::Result<Option<(Job, CancellationToken)>> {
+        let session = session.clone();
+        self.with_queue(move |state, queue| {
+            Ok(queue
+                .claim_next()?
+                .map(|job| (job.clone(), state.start_job(job.id, &session))))
+        })
+        .await
+    }
+
+    fn start_job(&self, id: u64, session: &CancellationToken) -> CancellationToken {
+        let cancellation = session.child();
+        self.running.lock().unwrap().insert(
+            id,
+            RunningJob {
+                cancellation: cancellation.clone(),
+                progress: JobProgress::default(),
+            },
+        );
+        cancellation
+    }
+
+    pub fn update_progress(&self, id: u64, progress: JobProgress) {
+        if let Some(job) = self.running.lock().unwrap().get_mut(&id) {
+            job.progress = progress;
+        }
+    }
+
+    pub fn end_job(&self, id: u64) {
+        self.running.lock().unwrap().remove(&id);
+    }
+
+    // resolves when a job is queued through the api, or after `timeout` so jobs added to
+    // the queue file by other means are picked up as well
+    pub async fn wait_for_jobs(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.added.notified()).await;
+    }
+
+    fn progress(&self, id: u64) -> Option<JobProgress> {
+        self.running
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|job| job.progress)
+    }
+
+    // returns false when the job isn't running
+    fn cancel_job(&self, id: u64) -> bool {
+        match self.running.lock().unwrap().get(&id) {
+            Some(job) => {
+                job.cancellation.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JobView<'a> {
+    #[serde(flatten)]
+    job: &'a Job,
+    progress: Option<JobProgress>,
+}
+
+#[derive(Deserialize)]
+struct NewJob {
+    lesson: usize,
+    part: usize,
+    #[serde(default)]
+    hq: bool,
+}
+
+#[derive(PartialEq, Debug)]
+enum Route {
+    Lessons,
+    Lesson(usize),
+    Jobs,
+    AddJob,
+    Job(u64),
+    DeleteJob(u64),
+}
+
+fn route(method: &Method, path: &str) -> Option<Route> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let route = match (method, segments.as_slice()) {
+        (&Method::GET, ["lessons"]) => Route::Lessons,
+        (&Method::GET, ["lessons", idx]) => Route::Lesson(idx.parse().ok()?),
+        (&Method::GET, ["jobs"]) => Route::Jobs,
+        (&Method::POST, ["jobs"]) => Route::AddJob,
+        (&Method::GET, ["jobs", id]) => Route::Job(id.parse().ok()?),
+        (&Method::DELETE, ["jobs", id]) => Route::DeleteJob(id.parse().ok()?),
+        _ => return None,
+    };
+    Some(route)
+}
+
+// serves the api on `addr` until `shutdown` is cancelled
+pub async fn serve(
+    addr: SocketAddr,
+    state: Arc<ControlState>,
+    client: Arc<ClientConnected>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        let client = client.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(state.clone(), client.clone(), request)
+            }))
+        }
+    });
+
+    let server = hyper::Server::try_bind(&addr)
+        .context(format!("could not listen on [{}]", addr))?
+        .serve(make_service);
+    println!("control api listening on [http://{}]", server.local_addr());
+
+    server
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await
+        .context("control api failed")
+}
+
+async fn handle(
+    state: Arc<ControlState>,
+    client: Arc<ClientConnected>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match route(request.method(), request.uri().path()) {
+        Some(route) => respond(&state, &client, route, request)
+            .await
+            .unwrap_or_else(|e| error(error_status(&e), &format!("{:#}", e))),
+        None => error(StatusCode::NOT_FOUND, "no such endpoint"),
+    };
+    Ok(response)
+}
+
+async fn respond(
+    state: &Arc<ControlState>,
+    client: &ClientConnected,
+    route: Route,
+    request: Request<Body>,
+) -> anyhow::Result<Response<Body>> {
+    let response = match route {
+        Route::Lessons => {
+            let lessons: Vec<_> = client
+                .get_lessons()
+                .await?
+                .iter()
+                .enumerate()
+                .map(|(index, lesson)| serde_json::json!({ "index": index, "title": lesson.title }))
+                .collect();
+            json(StatusCode::OK, &lessons)
+        }
+        Route::Lesson(idx) => {
+            let page = client.get_lesson(idx).await?;
+            let parts: Vec<_> = page
+                .parts
+                .iter()
+                .enumerate()
+                .map(|(index, part)| {
+                    serde_json::json!({
+                        "index": index,
+                        "title": part.title,
+                        "duration": part.duration,
+                    })
+                })
+                .collect();
+            let attachments: Vec<_> = page
+                .attachments
+                .iter()
+                .map(|attachment| {
+                    serde_json::json!({ "name": attachment.name, "url": attachment.url })
+                })
+                .collect();
+            json(
+                StatusCode::OK,
+                &serde_json::json!({
+                    "course": page.course,
+                    "parts": parts,
+                    "attachments": attachments,
+                }),
+            )
+        }
+        Route::Jobs => {
+            let jobs = state.with_queue(|_, queue| queue.jobs()).await?;
+            let views: Vec<_> = jobs.iter().map(|job| job_view(state, job)).collect();
+            json(StatusCode::OK, &views)
+        }
+        Route::AddJob => {
+            let body = hyper::body::to_bytes(request.into_body())
+                .await
+                .context("could not read request body")?;
+            let new_job: NewJob = match serde_json::from_slice(&body) {
+                Ok(new_job) => new_job,
+                Err(e) => return Ok(error(StatusCode::BAD_REQUEST, &e.to_string())),
+            };
+
+            // refuse parts that don't exist now, rather than queueing a job that can only fail
+            let (_, page) = match client.fetch_lesson(new_job.lesson).await {
+                Err(e) if e.is::<NoSuchIndex>() => {
+                    return Ok(error(StatusCode::BAD_REQUEST, &e.to_string()))
+                }
+                result => result?,
+            };
+            if new_job.part >= page.parts.len() {
+                let e = NoSuchIndex {
+                    kind: "part",
+                    index: new_job.part,
+                };
+                return Ok(error(StatusCode::BAD_REQUEST, &e.to_string()));
+            }
+
+            let id = state
+                .with_queue(move |_, queue| queue.add(new_job.lesson, new_job.part, new_job.hq))
+                .await?;
+            state.added.notify();
+            json(StatusCode::CREATED, &serde_json::json!({ "id": id }))
+        }
+        Route::Job(id) => {
+            let jobs = state.with_queue(|_, queue| queue.jobs()).await?;
+            match jobs.iter().find(|job| job.id == id) {
+                Some(job) => json(StatusCode::OK, &job_view(state, job)),
+                None => error(StatusCode::NOT_FOUND, &format!("no job [{}]", id)),
+            }
+        }
+        Route::DeleteJob(id) => {
+            // a cancelled job is dropped from the queue, its partial output stays resumable
+            let (cancelled, removed) = state
+                .with_queue(move |state, queue| Ok((state.cancel_job(id), queue.remove(id)?)))
+                .await?;
+            if removed || cancelled {
+                json(
+                    StatusCode::OK,
+                    &serde_json::json!({ "id": id, "cancelled": cancelled }),
+                )
+            } else {
+                error(StatusCode::NOT_FOUND, &format!("no job [{}]", id))
+            }
+        }
+    };
+    Ok(response)
+}
+
+// indices come straight from the request, so they're the caller's mistake
+fn error_status(e: &anyhow::Error) -> StatusCode {
+    if e.chain().any(|cause| cause.is::<NoSuchIndex>()) {
+        StatusCode::NOT_FOUND
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+fn job_view<'a>(state: &ControlState, job: &'a Job) -> JobView<'a> {
+    JobView {
+        job,
+        progress: state.progress(job.id),
+    }
+}
+
+fn json(status: StatusCode, value: &impl Serialize) -> Response<Body> {
+    let body = serde_json::to_vec(value).expect("api responses always serialize");
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .expect("api responses are always valid")
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    json(status, &serde_json::json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod server_tests {
+    use super::*;
+
+    #[test]
+    fn routes_requests() {
+        assert_eq!(route(&Method::GET, "/lessons"), Some(Route::Lessons));
+        assert_eq!(route(&Method::GET, "/lessons/3/"), Some(Route::Lesson(3)));
+        assert_eq!(route(&Method::POST, "/jobs"), Some(Route::AddJob));
+        assert_eq!(route(&Method::GET, "/jobs/12"), Some(Route::Job(12)));
+        assert_eq!(
+            route(&Method::DELETE, "/jobs/12"),
+            Some(Route::DeleteJob(12))
+        );
+
+        assert_eq!(route(&Method::DELETE, "/jobs"), None);
+        assert_eq!(route(&Method::GET, "/jobs/abc"), None);
+        assert_eq!(route(&Method::GET, "/"), None);
+    }
+
+    #[test]
+    fn missing_indices_are_not_found() {
+        let missing = anyhow::Error::from(NoSuchIndex {
+            kind: "lesson",
+            index: 40,
+        })
+        .context("failed to get lesson");
+        assert_eq!(error_status(&missing), StatusCode::NOT_FOUND);
+        assert_eq!(
+            error_status(&anyhow::anyhow!("connection reset")),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn tracks_and_cancels_running_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::new(&dir.path().join("queue.json"));
+        let id = queue.add(1, 1, false).unwrap();
+        let state = Arc::new(ControlState::new(queue));
+        let session = CancellationToken::new();
+
+        let (job, token) = state.claim_next(&session).await.unwrap().unwrap();
+        assert_eq!(job.id, id);
+        assert!(state.claim_next(&session).await.unwrap().is_none());
+        let progress = JobProgress {
+            segments_written: 2,
+            segments_total: 5,
+            bytes_written: 300,
+        };
+        state.update_progress(id, progress);
+        assert_eq!(state.progress(id), Some(progress));
+
+        assert!(state.cancel_job(id));
+        assert!(token.is_cancelled() && !session.is_cancelled());
+
+        state.end_job(id);
+        assert_eq!(state.progress(id), None);
+        assert!(!state.cancel_job(id));
+    }
+}
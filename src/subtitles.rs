@@ -0,0 +1,307 @@
+use anyhow::Context;
+
+// webvtt handling for caption tracks delivered as hls segment playlists
+// segments are parsed into cues, re-timed against the first segment's timestamp map,
+// de-duplicated (cues spanning a segment boundary are repeated in both) and written back out
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    pub id: Option<String>,
+    pub start_ms: i64,
+    pub end_ms: i64,
+    pub settings: String,
+    pub text: String,
+}
+
+struct Segment {
+    // (mpegts ticks, local ms) from `X-TIMESTAMP-MAP`
+    timestamp_map: Option<(i64, i64)>,
+    blocks: Vec<String>, // STYLE / REGION blocks
+    cues: Vec<Cue>,
+}
+
+pub fn stitch_webvtt<S: AsRef<str>>(segments: &[S]) -> anyhow::Result<String> {
+    let parsed = segments
+        .iter()
+        .enumerate()
+        .map(|(i, it)| {
+            parse_segment(it.as_ref()).context(format!("could not parse webvtt segment [{}]", i))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let base_map = parsed.iter().find_map(|it| it.timestamp_map);
+    let mut out = String::from("WEBVTT\n\n");
+    let mut cues: Vec<Cue> = vec![];
+
+    for (i, segment) in parsed.into_iter().enumerate() {
+        if i == 0 {
+            for block in &segment.blocks {
+                out.push_str(block);
+                out.push_str("\n\n");
+            }
+        }
+
+        let shift = match (base_map, segment.timestamp_map) {
+            (Some((base_ts, base_local)), Some((ts, local))) => {
+                (ts - base_ts) / 90 - (local - base_local)
+            }
+            _ => 0,
+        };
+
+        for mut cue in segment.cues {
+            cue.start_ms += shift;
+            cue.end_ms += shift;
+
+            let duplicate = cues.iter().rev().take(8).any(|it| {
+                it.start_ms == cue.start_ms && it.end_ms == cue.end_ms && it.text == cue.text
+            });
+            if !duplicate {
+                cues.push(cue);
+            }
+        }
+    }
+
+    for cue in cues {
+        if let Some(id) = &cue.id {
+            out.push_str(id);
+            out.push('\n');
+        }
+        out.push_str(&format!(
+            "{} --> {}",
+            format_timestamp(cue.start_ms, '.'),
+            format_timestamp(cue.end_ms, '.')
+        ));
+        if !cue.settings.is_empty() {
+            out.push(' ');
+            out.push_str(&cue.settings);
+        }
+        out.push('\n');
+        out.push_str(&cue.text);
+        out.push_str("\n\n");
+    }
+
+    Ok(out)
+}
+
+pub fn parse_cues(vtt: &str) -> anyhow::Result<Vec<Cue>> {
+    Ok(parse_segment(vtt)?.cues)
+}
+
+pub fn webvtt_to_srt(vtt: &str) -> anyhow::Result<String> {
+    let cues = parse_cues(vtt)?;
+    let mut out = String::new();
+
+    for (i, cue) in cues.iter().enumerate() {
+        out.push_str(&format!(
+            "{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            format_timestamp(cue.start_ms, ','),
+            format_timestamp(cue.end_ms, ','),
+            strip_vtt_markup(&cue.text)
+        ));
+    }
+
+    Ok(out)
+}
+
+fn parse_segment(vtt: &str) -> anyhow::Result<Segment> {
+    let vtt = vtt.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+    let mut blocks = vtt.split("\n\n").map(|b| b.trim_matches('\n'));
+
+    let header = blocks.next().context("empty webvtt segment")?;
+    if !header.starts_with("WEBVTT") {
+        anyhow::bail!("webvtt header not found");
+    }
+
+    let timestamp_map = header
+        .lines()
+        .find_map(|l| l.strip_prefix("X-TIMESTAMP-MAP="))
+        .map(parse_timestamp_map)
+        .transpose()?;
+
+    let mut segment = Segment {
+        timestamp_map,
+        blocks: vec![],
+        cues: vec![],
+    };
+
+    for block in blocks.filter(|b| !b.is_empty()) {
+        if block.starts_with("NOTE") {
+            continue;
+        }
+
+        if block.starts_with("STYLE") || block.starts_with("REGION") {
+            segment.blocks.push(block.into());
+            continue;
+        }
+
+        let mut lines = block.lines();
+        let first = lines.next().unwrap_or_default();
+        let (id, timing) = if first.contains("-->") {
+            (None, first)
+        } else {
+            let timing = lines
+                .next()
+                .context(format!("cue without timing line: [{}]", first))?;
+            (Some(first.to_string()), timing)
+        };
+
+        let (start, rest) = timing
+            .split_once("-->")
+            .context(format!("invalid cue timing: [{}]", timing))?;
+        let rest = rest.trim();
+        let (end, settings) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+
+        segment.cues.push(Cue {
+            id,
+            start_ms: parse_timestamp(start.trim())?,
+            end_ms: parse_timestamp(end.trim())?,
+            settings: settings.trim().into(),
+            text: lines.collect::<Vec<_>>().join("\n"),
+        });
+    }
+
+    Ok(segment)
+}
+
+// `MPEGTS:900000,LOCAL:00:00:00.000`, keys may come in either order
+fn parse_timestamp_map(value: &str) -> anyhow::Result<(i64, i64)> {
+    let mut mpegts = None;
+    let mut local = None;
+
+    for part in value.split(',') {
+        let (k, v) = part
+            .split_once(':')
+            .context(format!("invalid timestamp map entry: [{}]", part))?;
+        match k.trim() {
+            "MPEGTS" => mpegts = Some(v.trim().parse().context("invalid MPEGTS value")?),
+            "LOCAL" => local = Some(parse_timestamp(v.trim())?),
+            _ => {}
+        }
+    }
+
+    Ok((
+        mpegts.context("timestamp map has no MPEGTS")?,
+        local.context("timestamp map has no LOCAL")?,
+    ))
+}
+
+// `hh:mm:ss.ttt` or `mm:ss.ttt`
+fn parse_timestamp(ts: &str) -> anyhow::Result<i64> {
+    let (hms, millis) = ts
+        .split_once(['.', ','])
+        .context(format!("timestamp has no fractional part: [{}]", ts))?;
+    let millis: i64 = millis
+        .parse()
+        .context(format!("invalid timestamp: [{}]", ts))?;
+
+    let mut total = 0;
+    for part in hms.split(':') {
+        let part: i64 = part
+            .parse()
+            .context(format!("invalid timestamp: [{}]", ts))?;
+        total = total * 60 + part;
+    }
+
+    Ok(total * 1000 + millis)
+}
+
+fn format_timestamp(ms: i64, separator: char) -> String {
+    let ms = ms.max(0);
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        separator,
+        ms % 1000
+    )
+}
+
+// srt players only understand <b>, <i> and <u>, drop voice/class/timestamp tags
+fn strip_vtt_markup(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(open) = rest.find('<') {
+        out.push_str(&rest[..open]);
+        let close = match rest[open..].find('>') {
+            Some(close) => open + close,
+            None => {
+                rest = &rest[open..];
+                break;
+            }
+        };
+
+        let tag = &rest[open + 1..close];
+        let name = tag.trim_start_matches('/');
+        if matches!(name, "b" | "i" | "u") {
+            out.push_str(&rest[open..=close]);
+        }
+        rest = &rest[close + 1..];
+    }
+    out.push_str(rest);
+
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod subtitle_tests {
+    use super::*;
+
+    const SEGMENT_0: &str = "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\n\n\
+        1\n00:00:01.000 --> 00:00:04.000 align:start\n<v Teacher>Hello <i>there</i>\n\n\
+        00:00:09.000 --> 00:00:11.500\nspans the boundary\n";
+
+    const SEGMENT_1: &str = "WEBVTT\nX-TIMESTAMP-MAP=LOCAL:00:00:00.000,MPEGTS:900000\n\n\
+        NOTE a comment\n\n\
+        00:00:09.000 --> 00:00:11.500\nspans the boundary\n\n\
+        00:01:02.250 --> 00:01:05.000\nfish &amp; chips\n";
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("00:01:02.250").unwrap(), 62_250);
+        assert_eq!(parse_timestamp("01:02.250").unwrap(), 62_250);
+        assert_eq!(parse_timestamp("1:00:00.000").unwrap(), 3_600_000);
+        assert!(parse_timestamp("00:01").is_err());
+    }
+
+    #[test]
+    fn stitching_drops_headers_and_duplicate_cues() {
+        let stitched = stitch_webvtt(&[SEGMENT_0, SEGMENT_1]).unwrap();
+        assert_eq!(stitched.matches("WEBVTT").count(), 1);
+        assert_eq!(stitched.matches("spans the boundary").count(), 1);
+
+        let cues = parse_cues(&stitched).unwrap();
+        assert_eq!(cues.len(), 3);
+        assert_eq!(cues[0].id.as_deref(), Some("1"));
+        assert_eq!(cues[0].settings, "align:start");
+        assert_eq!(cues[2].start_ms, 62_250);
+    }
+
+    #[test]
+    fn stitching_applies_timestamp_map_offsets() {
+        let later = "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:1800000,LOCAL:00:00:00.000\n\n\
+            00:00:01.000 --> 00:00:02.000\nten seconds later\n";
+        let stitched = stitch_webvtt(&[SEGMENT_0, later]).unwrap();
+        let cues = parse_cues(&stitched).unwrap();
+        assert_eq!(cues.last().unwrap().start_ms, 11_000);
+    }
+
+    #[test]
+    fn converts_to_srt() {
+        let srt = webvtt_to_srt(&stitch_webvtt(&[SEGMENT_0, SEGMENT_1]).unwrap()).unwrap();
+        let expected = "1\n00:00:01,000 --> 00:00:04,000\nHello <i>there</i>\n\n\
+            2\n00:00:09,000 --> 00:00:11,500\nspans the boundary\n\n\
+            3\n00:01:02,250 --> 00:01:05,000\nfish & chips\n\n";
+        assert_eq!(srt, expected);
+    }
+
+    #[test]
+    fn rejects_non_webvtt_input() {
+        assert!(stitch_webvtt(&["not a subtitle"]).is_err());
+    }
+}
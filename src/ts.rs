@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+
+// sanity checks for decrypted mpeg transport stream segments
+// a wrong key still decrypts to something with valid padding often enough, and a truncated
+// response concatenates just fine, so we look at the stream structure before merging
+
+pub const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0x0000;
+const NULL_PID: u16 = 0x1FFF;
+
+#[derive(Debug, Default)]
+pub struct TsReport {
+    pub packets: usize,
+    pub has_pat: bool,
+    pub has_pmt: bool,
+    pub issues: Vec<String>,
+}
+
+impl TsReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl std::fmt::Display for TsReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_valid() {
+            return write!(f, "valid transport stream, [{}] packets", self.packets);
+        }
+        write!(f, "invalid transport stream: {}", self.issues.join("; "))
+    }
+}
+
+pub struct Packet<'a> {
+    pub pid: u16,
+    pub payload_unit_start: bool,
+    pub continuity_counter: u8,
+    pub discontinuity: bool,
+    pub adaptation: Option<&'a [u8]>, // without the length byte
+    pub payload: Option<&'a [u8]>,
+}
+
+// parses a single 188 byte packet, None if it's malformed
+pub fn parse_packet(data: &[u8]) -> Option<Packet<'_>> {
+    if data.len() != PACKET_SIZE || data[0] != SYNC_BYTE {
+        return None;
+    }
+
+    let pid = (u16::from(data[1] & 0x1F) << 8) | u16::from(data[2]);
+    let adaptation_control = (data[3] >> 4) & 0x03;
+    let mut offset = 4;
+
+    let adaptation = if adaptation_control & 0x02 != 0 {
+        let len = data[4] as usize;
+        if 5 + len > PACKET_SIZE {
+            return None;
+        }
+        offset = 5 + len;
+        Some(&data[5..5 + len])
+    } else {
+        None
+    };
+
+    let payload = if adaptation_control & 0x01 != 0 {
+        Some(&data[offset..])
+    } else {
+        None
+    };
+
+    Some(Packet {
+        pid,
+        payload_unit_start: data[1] & 0x40 != 0,
+        continuity_counter: data[3] & 0x0F,
+        discontinuity: adaptation.is_some_and(|a| !a.is_empty() && a[0] & 0x80 != 0),
+        adaptation,
+        payload,
+    })
+}
+
+pub fn validate_segment(data: &[u8]) -> TsReport {
+    let mut report = TsReport::default();
+
+    if data.is_empty() {
+        report.issues.push("segment is empty".into());
+        return report;
+    }
+
+    if !data.len().is_multiple_of(PACKET_SIZE) {
+        report.issues.push(format!(
+            "length [{}] is not a multiple of [{}], segment is likely truncated",
+            data.len(),
+            PACKET_SIZE
+        ));
+    }
+
+    let mut pmt_pids: Vec<u16> = vec![];
+    let mut counters: HashMap<u16, u8> = HashMap::new();
+    let mut bad_sync = 0;
+    let mut continuity_errors = 0;
+
+    for (i, chunk) in data.chunks_exact(PACKET_SIZE).enumerate() {
+        report.packets += 1;
+
+        let packet = match parse_packet(chunk) {
+            Some(packet) => packet,
+            None => {
+                bad_sync += 1;
+                if bad_sync == 1 {
+                    report.issues.push(format!(
+                        "packet [{}] at byte [{}] has no sync byte or a malformed header",
+                        i,
+                        i * PACKET_SIZE
+                    ));
+                }
+                continue;
+            }
+        };
+
+        if packet.pid == NULL_PID {
+            continue;
+        }
+
+        // counters only advance on packets carrying a payload
+        if packet.payload.is_some() {
+            if let Some(&last) = counters.get(&packet.pid) {
+                let expected = (last + 1) & 0x0F;
+                let duplicate = packet.continuity_counter == last;
+                if !packet.discontinuity && packet.continuity_counter != expected && !duplicate {
+                    continuity_errors += 1;
+                    if continuity_errors == 1 {
+                        report.issues.push(format!(
+                            "continuity error on pid [{:#x}] at packet [{}]: expected [{}], found [{}]",
+                            packet.pid, i, expected, packet.continuity_counter
+                        ));
+                    }
+                }
+            }
+            counters.insert(packet.pid, packet.continuity_counter);
+        }
+
+        let section = match packet.payload.filter(|_| packet.payload_unit_start) {
+            Some(payload) => psi_section(payload),
+            None => None,
+        };
+
+        if packet.pid == PAT_PID {
+            if let Some(section) = section.filter(|s| s.first() == Some(&0x00)) {
+                report.has_pat = true;
+                for pid in pat_program_map_pids(section) {
+                    if !pmt_pids.contains(&pid) {
+                        pmt_pids.push(pid);
+                    }
+                }
+            }
+        } else if pmt_pids.contains(&packet.pid) && section.and_then(|s| s.first()) == Some(&0x02) {
+            report.has_pmt = true;
+        }
+    }
+
+    if bad_sync > 1 {
+        report
+            .issues
+            .push(format!("[{}] packets without a valid sync byte", bad_sync));
+    }
+
+    if continuity_errors > 1 {
+        report
+            .issues
+            .push(format!("[{}] continuity counter errors", continuity_errors));
+    }
+
+    if !report.has_pat {
+        report.issues.push("no program association table".into());
+    } else if !report.has_pmt {
+        report.issues.push("no program map table".into());
+    }
+
+    report
+}
+
+// decoding timestamp of a pes packet starting in this payload, falling back to the
+// presentation timestamp, in 90khz ticks
+// dts is used when present since pts isn't monotonic for streams with b-frames
+pub fn pes_timestamp(payload: &[u8]) -> Option<u64> {
+    if payload.len() < 14 || payload[..3] != [0x00, 0x00, 0x01] {
+        return None;
+    }
+
+    // only audio, video and private stream 1 carry the optional pes header
+    let stream_id = payload[3];
+    if !(stream_id == 0xBD || (0xC0..=0xEF).contains(&stream_id)) {
+        return None;
+    }
+
+    match (payload[7] >> 6) & 0x03 {
+        0b11 if payload.len() >= 19 => Some(decode_timestamp(&payload[14..19])),
+        0b10 | 0b11 => Some(decode_timestamp(&payload[9..14])),
+        _ => None,
+    }
+}
+
+// 33 bit timestamp spread over 5 bytes with marker bits
+fn decode_timestamp(b: &[u8]) -> u64 {
+    (u64::from(b[0] >> 1) & 0x07) << 30
+        | u64::from(b[1]) << 22
+        | u64::from(b[2] >> 1) << 15
+        | u64::from(b[3]) << 7
+        | u64::from(b[4] >> 1)
+}
+
+// skips the pointer field of a psi payload
+fn psi_section(payload: &[u8]) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    payload.get(1 + pointer..)
+}
+
+fn pat_program_map_pids(section: &[u8]) -> Vec<u16> {
+    if section.len() < 8 {
+        return vec![];
+    }
+
+    let section_length = ((usize::from(section[1]) & 0x0F) << 8) | usize::from(section[2]);
+    // header is 8 bytes, the trailing 4 are the crc
+    let end = (3 + section_length).saturating_sub(4).min(section.len());
+
+    section
+        .get(8..end)
+        .unwrap_or_default()
+        .chunks_exact(4)
+        .filter(|entry| entry[0] != 0 || entry[1] != 0) // program 0 is the network pid
+        .map(|entry| (u16::from(entry[2] & 0x1F) << 8) | u16::from(entry[3]))
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod test_stream {
+    use super::*;
+
+    // builds a packet with the given pid, counter and payload, padded with 0xff
+    pub fn packet(pid: u16, pusi: bool, cc: u8, payload: &[u8]) -> Vec<u8> {
+        let mut p = vec![
+            SYNC_BYTE,
+            (if pusi { 0x40 } else { 0 }) | ((pid >> 8) as u8 & 0x1F),
+            pid as u8,
+            0x10 | (cc & 0x0F),
+        ];
+        p.extend_from_slice(payload);
+        p.resize(PACKET_SIZE, 0xFF);
+        p
+    }
+
+    pub fn pat(pmt_pid: u16) -> Vec<u8> {
+        // pointer, table id, section length 13, ts id, version, section numbers
+        let mut payload = vec![0x00, 0x00, 0xB0, 0x0D, 0x00, 0x01, 0xC1, 0x00, 0x00];
+        // program 1 -> pmt pid
+        payload.extend_from_slice(&[0x00, 0x01, 0xE0 | (pmt_pid >> 8) as u8, pmt_pid as u8]);
+        // crc, not checked
+        payload.extend_from_slice(&[0, 0, 0, 0]);
+        packet(PAT_PID, true, 0, &payload)
+    }
+
+    pub fn pmt(pmt_pid: u16) -> Vec<u8> {
+        packet(pmt_pid, true, 0, &[0x00, 0x02, 0xB0, 0x12])
+    }
+
+    // pes header carrying only a pts
+    pub fn pes(pts: u64) -> Vec<u8> {
+        vec![
+            0x00,
+            0x00,
+            0x01,
+            0xE0,
+            0x00,
+            0x00,
+            0x80,
+            0x80,
+            0x05,
+            0x21 | ((pts >> 29) & 0x0E) as u8,
+            (pts >> 22) as u8,
+            0x01 | ((pts >> 14) & 0xFE) as u8,
+            (pts >> 7) as u8,
+            0x01 | ((pts << 1) & 0xFE) as u8,
+        ]
+    }
+
+    // segment whose video frames start at `pts` and are `step` ticks apart
+    pub fn timed_segment(frames: u8, pts: u64, step: u64) -> Vec<u8> {
+        let mut data = pat(0x1000);
+        data.extend(pmt(0x1000));
+        for cc in 0..frames {
+            data.extend(packet(0x100, true, cc, &pes(pts + u64::from(cc) * step)));
+        }
+        data
+    }
+
+    pub fn segment(video_packets: u8) -> Vec<u8> {
+        let mut data = pat(0x1000);
+        data.extend(pmt(0x1000));
+        for cc in 0..video_packets {
+            data.extend(packet(0x100, cc == 0, cc, &[]));
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod ts_tests {
+    use super::test_stream::*;
+    use super::*;
+
+    #[test]
+    fn accepts_well_formed_segment() {
+        let report = validate_segment(&segment(20));
+        assert!(report.is_valid(), "{}", report);
+        assert_eq!(report.packets, 22);
+    }
+
+    #[test]
+    fn rejects_truncated_segment() {
+        let mut data = segment(4);
+        data.truncate(data.len() - 10);
+        let report = validate_segment(&data);
+        assert!(!report.is_valid());
+        assert!(report.issues[0].contains("truncated"));
+    }
+
+    #[test]
+    fn rejects_garbage_from_wrong_key() {
+        let data: Vec<u8> = (0..PACKET_SIZE * 4).map(|i| (i * 31 % 251) as u8).collect();
+        let report = validate_segment(&data);
+        assert!(!report.is_valid());
+        assert!(!report.has_pat);
+    }
+
+    #[test]
+    fn rejects_missing_pmt() {
+        let mut data = pat(0x1000);
+        data.extend(packet(0x100, true, 0, &[]));
+        let report = validate_segment(&data);
+        assert!(report.has_pat);
+        assert!(!report.has_pmt);
+        assert!(!report.is_valid());
+    }
+
+    #[test]
+    fn detects_continuity_errors() {
+        let mut data = segment(3);
+        data.extend(packet(0x100, false, 7, &[]));
+        let report = validate_segment(&data);
+        assert!(!report.is_valid());
+        assert!(report.issues[0].contains("continuity"));
+    }
+
+    #[test]
+    fn reads_pes_timestamps() {
+        let pts = (1 << 32) + 123_456;
+        assert_eq!(pes_timestamp(&pes(pts)), Some(pts));
+        assert_eq!(pes_timestamp(&[0xFF; 20]), None);
+    }
+
+    #[test]
+    fn counters_wrap_around() {
+        let report = validate_segment(&segment(40));
+        assert!(report.is_valid(), "{}", report);
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 
@@ -65,6 +65,123 @@ pub fn matching_bracket_substring<'a>(input: &'a str, opener: char) -> anyhow::R
     Ok(&input[start_idx..=end_idx])
 }
 
+// same as `matching_bracket_substring`, but skips over javascript string literals
+// (single, double and template quoted, with escapes) and comments while counting,
+// so a bracket inside `"a ] b"` or `// ]` doesn't end the match early
+// template literal substitutions (`${...}`) are treated as part of the string
+pub fn matching_bracket_substring_js(input: &str, opener: char) -> anyhow::Result<&str> {
+    if !VALID_OPENERS.contains(&opener) {
+        return Err(anyhow::anyhow!(
+            "char [{}] is not a supported opener",
+            &opener
+        ));
+    }
+
+    let closer = BRACKET_CLOSERS[&opener];
+    let bytes = input.as_bytes();
+    let mut counter = 0;
+    let mut start_idx = None;
+    let mut i = 0;
+
+    // all the delimiters we care about are ascii, so walking bytes is safe:
+    // utf-8 continuation bytes never collide with them
+    while i < bytes.len() {
+        let c = bytes[i];
+        match c {
+            b'"' | b'\'' | b'`' => {
+                i += 1;
+                loop {
+                    match bytes.get(i) {
+                        None => anyhow::bail!("unterminated string literal"),
+                        Some(b'\\') => i += 2,
+                        Some(&b) if b == c => break,
+                        Some(_) => i += 1,
+                    }
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                i += input[i..].find('\n').unwrap_or(input.len() - i);
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += 2
+                    + input[i + 2..]
+                        .find("*/")
+                        .context("unterminated block comment")?
+                    + 1;
+            }
+            _ if c == opener as u8 => {
+                if start_idx.is_none() {
+                    start_idx = Some(i);
+                }
+
+                counter += 1;
+            }
+            _ if c == closer as u8 => {
+                if start_idx.is_none() {
+                    anyhow::bail!("found closer before opener in matching bracket search");
+                }
+                counter -= 1;
+
+                if counter == 0 {
+                    // found matching bracket
+                    return Ok(&input[start_idx.unwrap_or_default()..=i]);
+                }
+            }
+            _ => {}
+        }
+
+        i += 1;
+    }
+
+    if start_idx.is_none() {
+        anyhow::bail!("no brackets found");
+    }
+
+    anyhow::bail!("mismatched brackets")
+}
+
+// names scraped from pages end up on disk, keep them to a single path component
+pub fn sanitize_file_name(name: &str) -> String {
+    let cleaned: String = name
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    let cleaned = cleaned.trim_matches('.').to_string();
+    if cleaned.is_empty() {
+        return "unnamed".into();
+    }
+    cleaned
+}
+
+// `1.5 MiB` style, binary multiples like `ratelimit::parse_rate`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// for `#[serde(serialize_with)]`, keys and ivs read better as hex than as number arrays
+pub fn serialize_hex<S: serde::Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    serializer.serialize_str(&hex)
+}
+
 use std::num::ParseIntError;
 pub fn decode_hex(s: &str) -> Result<Vec<u8>> {
     let mut start_idx = 0;
@@ -95,6 +212,35 @@ mod hex_conversion_tests {
     }
 }
 
+#[cfg(test)]
+mod format_size_tests {
+    use super::*;
+
+    #[test]
+    fn uses_binary_units() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(300 << 20), "300.0 MiB");
+        assert_eq!(format_size(5 << 40), "5.0 TiB");
+    }
+}
+
+#[cfg(test)]
+mod sanitize_file_name_tests {
+    use super::*;
+
+    #[test]
+    fn strips_path_separators_and_reserved_characters() {
+        assert_eq!(sanitize_file_name("../../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(
+            sanitize_file_name("brief: week 1?.pdf"),
+            "brief_ week 1_.pdf"
+        );
+        assert_eq!(sanitize_file_name(" .. "), "unnamed");
+        assert_eq!(sanitize_file_name("日本語.pdf"), "日本語.pdf");
+    }
+}
+
 #[cfg(test)]
 mod matching_bracket_tests {
     use super::*;
@@ -137,3 +283,53 @@ mod matching_bracket_tests {
         assert!(result.is_err());
     }
 }
+
+#[cfg(test)]
+mod matching_bracket_js_tests {
+    use super::*;
+
+    #[test]
+    fn unsupported_character_is_rejected() {
+        let result = matching_bracket_substring_js("lmao", 'o');
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn will_parse_nested_brackets() {
+        let result = matching_bracket_substring_js("var a = [[1], [2, [3]]];", '[');
+        assert_eq!(result.unwrap(), "[[1], [2, [3]]]");
+    }
+
+    #[test]
+    fn brackets_in_strings_are_ignored() {
+        let input = r#"x = [ "a]", 'b]', `c]`, "d\"]" ] tail ]"#;
+        let result = matching_bracket_substring_js(input, '[');
+        assert_eq!(result.unwrap(), r#"[ "a]", 'b]', `c]`, "d\"]" ]"#);
+    }
+
+    #[test]
+    fn brackets_in_comments_are_ignored() {
+        let input = "{ a: 1, // }\n /* } */ b: 2 } }";
+        let result = matching_bracket_substring_js(input, '{');
+        assert_eq!(result.unwrap(), "{ a: 1, // }\n /* } */ b: 2 }");
+    }
+
+    #[test]
+    fn handles_unicode_before_and_inside_match() {
+        let input = "ünïcödé 😀 = ['日本語', \"é]\", [ß]] 🎉";
+        let result = matching_bracket_substring_js(input, '[');
+        assert_eq!(result.unwrap(), "['日本語', \"é]\", [ß]]");
+    }
+
+    #[test]
+    fn will_fail_on_unterminated_string() {
+        let result = matching_bracket_substring_js("[ 'abc ]", '[');
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn will_fail_on_unbalanced_brackets() {
+        let result = matching_bracket_substring_js("[[[[[[]]]]", '[');
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+// lesson parts `selene watch` has already handled, so each poll of the dashboard only
+// downloads what was unlocked since. parts are recorded once downloaded (or when taking a
+// baseline), a failed part is tried again on the next poll
+#[derive(Serialize, Deserialize, Default)]
+pub struct SeenParts {
+    parts: BTreeSet<String>, // see `archive::part_id`
+}
+
+impl SeenParts {
+    // a missing file means nothing has been seen yet
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)
+                .context(format!("invalid watch state [{}]", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).context(format!("could not read watch state [{}]", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(self).context("could not serialize watch state")?;
+        let tmp = crate::output::part_path(path);
+        std::fs::write(&tmp, json)
+            .context(format!("could not write watch state [{}]", tmp.display()))?;
+        std::fs::rename(&tmp, path).context(format!(
+            "could not replace watch state [{}]",
+            path.display()
+        ))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    pub fn contains(&self, part_id: &str) -> bool {
+        self.parts.contains(part_id)
+    }
+
+    pub fn insert(&mut self, part_id: &str) {
+        self.parts.insert(part_id.into());
+    }
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+
+    #[test]
+    fn remembers_parts_across_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watch.json");
+
+        let mut seen = SeenParts::load(&path).unwrap();
+        assert!(seen.is_empty());
+        seen.insert("lesson.php?id=1:/a.m3u8");
+        seen.save(&path).unwrap();
+
+        let seen = SeenParts::load(&path).unwrap();
+        assert!(seen.contains("lesson.php?id=1:/a.m3u8"));
+        assert!(!seen.contains("lesson.php?id=2:/a.m3u8"));
+    }
+}